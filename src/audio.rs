@@ -1,10 +1,687 @@
 use anyhow::Context as _;
 use pipewire::spa;
+use std::cell::RefCell;
+use std::ptr::NonNull;
+use std::rc::Rc;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, AtomicU32, AtomicU64, AtomicUsize, Ordering};
+
+/// driver priority of the stream with `--driver`, above the ones of sound cards
+const DRIVER_PRIORITY: &str = "10000";
+/// size of the buffer between the packet pipeline and the audio thread, in
+/// bytes. That's over 5 seconds of 48 kHz stereo S16LE audio.
+const RING_SIZE: usize = 1 << 20;
+/// number of bytes at the end of a cycle that are kept to conceal underruns
+const LAST_SIZE: usize = crate::usb::MAX_ISOCHRONOUS_PAYLOAD;
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct StreamFormat {
+    pub format: spa::param::audio::AudioFormat,
+    pub rate: u32,
+    pub channels: Vec<spa::sys::spa_audio_channel>,
+}
+
+/// The volume the host set on the feature units of the device.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Volume {
+    pub mute: bool,
+    /// linear gain per channel
+    pub channels: Vec<f32>,
+}
+
+impl StreamFormat {
+    /// returns the size of a frame, which is never 0.
+    fn stride(&self) -> anyhow::Result<usize> {
+        anyhow::ensure!(!self.channels.is_empty(), "format without channels");
+        Ok(get_channel_size(self.format)? * self.channels.len())
+    }
+
+    /// returns the parameters of a stream with this format.
+    fn to_params(&self, options: &Options) -> anyhow::Result<[Vec<u8>; 2]> {
+        Ok([self.to_pod()?, options.process_latency_pod()])
+    }
+
+    fn to_pod(&self) -> anyhow::Result<Vec<u8>> {
+        let mut audio_info = spa::param::audio::AudioInfoRaw::new();
+        audio_info.set_format(self.format);
+        audio_info.set_rate(self.rate);
+        audio_info.set_channels(self.channels.len().try_into().unwrap());
+
+        let mut position = [0; spa::param::audio::MAX_CHANNELS];
+        for (index, channel) in self.channels.iter().enumerate() {
+            *(position.get_mut(index).context("too many channels")?) = *channel;
+        }
+        audio_info.set_position(position);
+
+        Ok(spa::pod::serialize::PodSerializer::serialize(
+            std::io::Cursor::new(Vec::new()),
+            &spa::pod::Value::Object(spa::pod::Object {
+                type_: spa::sys::SPA_TYPE_OBJECT_Format,
+                id: spa::sys::SPA_PARAM_EnumFormat,
+                properties: audio_info.into(),
+            }),
+        )
+        .unwrap()
+        .0
+        .into_inner())
+    }
+}
+
+/// Where the audio of a stream goes.
+#[derive(Clone, Debug)]
+pub enum Output {
+    PipeWire,
+    File(crate::file::Container, std::path::PathBuf),
+    /// the samples without any header
+    Raw(std::path::PathBuf),
+    /// the samples without any header, for piping them into another program
+    Stdout,
+    /// nowhere, e.g. to measure the throughput
+    Null,
+}
+
+impl Output {
+    /// creates the sink of the stream `name`.
+    pub fn sink(&self, name: &str, options: Options) -> anyhow::Result<Box<dyn AudioSink>> {
+        Ok(match self {
+            Self::PipeWire => Box::new(PipeWireSink::new(name.to_string(), options)),
+            Self::File(container, path) => Box::new(crate::file::FileSink::new(
+                *container,
+                path.clone(),
+                options.rotation,
+            )),
+            Self::Raw(path) => {
+                let file = std::fs::File::create(path)
+                    .with_context(|| format!("failed to create {}", path.display()))?;
+                Box::new(RawSink::new(std::io::BufWriter::new(file)))
+            }
+            Self::Stdout => Box::new(RawSink::new(std::io::BufWriter::new(Stdout(
+                std::io::stdout(),
+            )))),
+            Self::Null => Box::new(NullSink::default()),
+        })
+    }
+}
+
+/// A destination of the captured audio.
+pub trait AudioSink {
+    /// starts the output, once the format is known.
+    fn open(&mut self, format: StreamFormat, active: bool) -> anyhow::Result<()>;
+
+    /// passes on the audio of one USB packet.
+    fn write(&mut self, data: &[u8]) -> anyhow::Result<()>;
+
+    /// switches to a different format, which applies to the following writes.
+    fn set_format(&mut self, format: StreamFormat) -> anyhow::Result<()>;
+
+    /// pauses or resumes the output, depending on whether the host streams
+    /// audio.
+    fn set_active(&mut self, _active: bool) {}
+
+    /// updates the speed of the host clock relative to the system clock.
+    fn set_drift(&mut self, _ratio: f64) {}
+
+    /// mirrors the volume the host set on the device.
+    fn set_volume(&mut self, _volume: &Volume) {}
+
+    /// logs the problems of the output since the last report.
+    fn report(&mut self) {}
+}
+
+/// Plays the audio through a pipewire source node, which runs in its own
+/// thread.
+pub struct PipeWireSink {
+    name: String,
+    options: Options,
+    commands: Option<pipewire::channel::Sender<Command>>,
+    queue: Option<QueueSender>,
+}
+
+impl PipeWireSink {
+    pub fn new(name: String, options: Options) -> Self {
+        Self {
+            name,
+            options,
+            commands: None,
+            queue: None,
+        }
+    }
+
+    fn send(&self, command: Command) {
+        if let Some(commands) = &self.commands
+            && commands.send(command).is_err()
+        {
+            log::error!("audio thread of {} is gone", self.name);
+        }
+    }
+}
+
+impl AudioSink for PipeWireSink {
+    fn open(&mut self, format: StreamFormat, active: bool) -> anyhow::Result<()> {
+        let (sender, receiver) = pipewire::channel::channel();
+        let name = self.name.clone();
+        let options = self.options;
+        let (queue, receiver_queue) = queue();
+        let (started, startup) = std::sync::mpsc::sync_channel(1);
+        std::thread::spawn(move || {
+            let result = run(
+                name.clone(),
+                format,
+                active,
+                options,
+                receiver,
+                receiver_queue,
+                &started,
+            );
+            // nobody waits for errors once the stream is running
+            if let Err(e) = result
+                && let Err(std::sync::mpsc::SendError(Err(e))) = started.send(Err(e))
+            {
+                log::error!("audio thread of {name} failed: {e:#}");
+            }
+        });
+
+        startup
+            .recv()
+            .with_context(|| format!("audio thread of {} stopped", self.name))?
+            .with_context(|| format!("failed to start the pipewire stream {}", self.name))?;
+        self.commands = Some(sender);
+        self.queue = Some(queue);
+        Ok(())
+    }
+
+    fn write(&mut self, data: &[u8]) -> anyhow::Result<()> {
+        let Some(queue) = &mut self.queue else {
+            return Ok(());
+        };
+
+        if self.options.blocking {
+            // wait for the audio thread to keep playback in real time
+            let start = std::time::Instant::now();
+            while queue.is_full() && start.elapsed() < core::time::Duration::from_millis(100) {
+                std::thread::sleep(core::time::Duration::from_millis(1));
+            }
+        }
+
+        if !queue.push(data) {
+            log::warn!("audio buffer full, drop");
+            return Ok(());
+        }
+
+        if self.options.driver && queue.is_cycle_ready() {
+            self.send(Command::Trigger);
+        }
+        Ok(())
+    }
+
+    fn set_format(&mut self, format: StreamFormat) -> anyhow::Result<()> {
+        format
+            .stride()
+            .with_context(|| format!("unsupported format {format:?}"))?;
+        self.send(Command::SetFormat(format));
+        Ok(())
+    }
+
+    fn set_active(&mut self, active: bool) {
+        self.send(Command::SetActive(active));
+    }
+
+    fn set_drift(&mut self, ratio: f64) {
+        self.send(Command::ClockDrift(ratio));
+    }
+
+    fn set_volume(&mut self, volume: &Volume) {
+        if self.options.mirror_volume {
+            self.send(Command::SetVolume(volume.clone()));
+        }
+    }
+
+    fn report(&mut self) {
+        let Some(queue) = &self.queue else {
+            return;
+        };
+
+        let state = &queue.state;
+        let underruns = state.underruns.swap(0, Ordering::Relaxed);
+        let concealed = state.concealed.swap(0, Ordering::Relaxed);
+        let missing_buffers = state.missing_buffers.swap(0, Ordering::Relaxed);
+        if underruns > 0 {
+            log::debug!(
+                "{}: {underruns} underruns, concealed {concealed} bytes",
+                self.name
+            );
+        }
+        if missing_buffers > 0 {
+            // stdout might carry the audio of the other stream
+            log::warn!("{}: out of buffers in {missing_buffers} cycles", self.name);
+        }
+    }
+}
+
+/// Writes the samples as they are, without any header.
+pub struct RawSink<W> {
+    writer: W,
+    /// the format of the samples written so far
+    format: Option<StreamFormat>,
+}
+
+impl<W> RawSink<W> {
+    pub fn new(writer: W) -> Self {
+        Self {
+            writer,
+            format: None,
+        }
+    }
+}
+
+impl<W: std::io::Write> AudioSink for RawSink<W> {
+    fn open(&mut self, format: StreamFormat, _active: bool) -> anyhow::Result<()> {
+        self.format = Some(format);
+        Ok(())
+    }
+
+    fn write(&mut self, data: &[u8]) -> anyhow::Result<()> {
+        self.writer
+            .write_all(data)
+            .context("failed to write raw audio")
+    }
+
+    /// fails if the format changes, since the reader can't tell where the
+    /// samples of the new format start.
+    fn set_format(&mut self, format: StreamFormat) -> anyhow::Result<()> {
+        if let Some(current) = &self.format
+            && *current != format
+        {
+            anyhow::bail!("raw audio can't change its format to {format:?}");
+        }
+
+        self.format = Some(format);
+        Ok(())
+    }
+}
+
+/// Writes to stdout and closes it when dropped, so the program reading the
+/// audio sees the end of the stream.
+struct Stdout(std::io::Stdout);
+
+impl std::io::Write for Stdout {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.0.write(buf)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.0.flush()
+    }
+}
+
+impl Drop for Stdout {
+    fn drop(&mut self) {
+        let _ = std::io::Write::flush(&mut self.0);
+
+        // replaced instead of closed, so the descriptor can't be reused by
+        // another file
+        match std::fs::OpenOptions::new().write(true).open("/dev/null") {
+            // SAFETY: both descriptors are valid
+            Ok(null) => unsafe {
+                libc::dup2(std::os::fd::AsRawFd::as_raw_fd(&null), libc::STDOUT_FILENO);
+            },
+            Err(e) => log::error!("failed to close stdout: {e}"),
+        }
+    }
+}
+
+/// Discards the audio and reports the amount at the end.
+#[derive(Default)]
+pub struct NullSink {
+    bytes: u64,
+    start: Option<std::time::Instant>,
+}
+
+impl AudioSink for NullSink {
+    fn open(&mut self, _format: StreamFormat, _active: bool) -> anyhow::Result<()> {
+        self.start = Some(std::time::Instant::now());
+        Ok(())
+    }
+
+    fn write(&mut self, data: &[u8]) -> anyhow::Result<()> {
+        self.bytes += data.len() as u64;
+        Ok(())
+    }
+
+    fn set_format(&mut self, _format: StreamFormat) -> anyhow::Result<()> {
+        Ok(())
+    }
+}
+
+impl Drop for NullSink {
+    fn drop(&mut self) {
+        if let Some(start) = self.start {
+            let elapsed = start.elapsed().as_secs_f64();
+            log::info!(
+                "discarded {} bytes of audio in {elapsed:.1}s ({:.1} kB/s)",
+                self.bytes,
+                self.bytes as f64 / elapsed / 1e3
+            );
+        }
+    }
+}
+
+/// Settings of the audio outputs given on the command line.
+#[derive(Clone, Copy, Debug)]
+pub struct Options {
+    /// whether the streams drive the pipewire graph
+    pub driver: bool,
+    /// audio buffered on top of one graph cycle to absorb the jitter of the
+    /// USB packets
+    pub latency: core::time::Duration,
+    /// whether the packet pipeline waits for the audio thread instead of
+    /// dropping audio, e.g. when replaying a recording
+    pub blocking: bool,
+    /// when to start a new file
+    pub rotation: crate::file::Rotation,
+    /// whether the volume set by the host is applied to the pipewire nodes
+    pub mirror_volume: bool,
+}
+
+impl Options {
+    /// returns the ProcessLatency param, which tells pipewire about the
+    /// latency of the jitter buffer.
+    fn process_latency_pod(&self) -> Vec<u8> {
+        spa::pod::serialize::PodSerializer::serialize(
+            std::io::Cursor::new(Vec::new()),
+            &spa::pod::Value::Object(spa::pod::Object {
+                type_: spa::sys::SPA_TYPE_OBJECT_ParamProcessLatency,
+                id: spa::sys::SPA_PARAM_ProcessLatency,
+                properties: vec![spa::pod::Property::new(
+                    spa::sys::SPA_PARAM_PROCESS_LATENCY_ns,
+                    spa::pod::Value::Long(
+                        i64::try_from(self.latency.as_nanos()).unwrap_or(i64::MAX),
+                    ),
+                )],
+            }),
+        )
+        .unwrap()
+        .0
+        .into_inner()
+    }
+}
+
+/// Commands sent from the packet pipeline to the audio thread.
+#[derive(Debug)]
+enum Command {
+    /// resumes or pauses the stream, depending on whether the host streams audio
+    SetActive(bool),
+    /// renegotiates the stream format
+    SetFormat(StreamFormat),
+    /// updates the speed of the host clock relative to the system clock
+    ClockDrift(f64),
+    /// updates the channelVolumes and mute props of the node
+    SetVolume(Volume),
+    /// starts a graph cycle, if the stream drives the graph
+    Trigger,
+}
+
+/// State shared by both ends of the queue.
+struct QueueState {
+    /// number of bytes that have to be queued to start the next graph cycle
+    cycle: AtomicUsize,
+    /// whether the next graph cycle hasn't been triggered yet
+    armed: AtomicBool,
+    /// whether the audio thread has to discard the queued audio
+    flush: AtomicBool,
+    /// number of underruns since the last report, which the audio thread
+    /// can't log itself
+    underruns: AtomicU64,
+    /// number of bytes concealed since the last report
+    concealed: AtomicU64,
+    /// number of graph cycles without a buffer since the last report
+    missing_buffers: AtomicU64,
+}
+
+/// returns both ends of the queue passing audio from the packet pipeline to
+/// the audio thread.
+fn queue() -> (QueueSender, QueueReceiver) {
+    let (producer, consumer) = crate::ring::ring(RING_SIZE);
+    let state = Arc::new(QueueState {
+        // the first packet triggers a cycle, which tells the actual size
+        cycle: AtomicUsize::new(1),
+        armed: AtomicBool::new(true),
+        flush: AtomicBool::new(false),
+        underruns: AtomicU64::new(0),
+        concealed: AtomicU64::new(0),
+        missing_buffers: AtomicU64::new(0),
+    });
+
+    (
+        QueueSender {
+            producer,
+            state: state.clone(),
+        },
+        QueueReceiver { consumer, state },
+    )
+}
+
+/// The end of the queue used by the packet pipeline.
+struct QueueSender {
+    producer: crate::ring::Producer,
+    state: Arc<QueueState>,
+}
+
+impl QueueSender {
+    /// appends the audio of one packet, returns false if there's no room.
+    fn push(&mut self, data: &[u8]) -> bool {
+        self.producer.push(data)
+    }
+
+    /// returns true, if the next graph cycle and the jitter buffer are
+    /// covered already.
+    fn is_full(&self) -> bool {
+        self.producer.len() >= self.state.cycle.load(Ordering::Relaxed)
+    }
+
+    /// returns true once per graph cycle, as soon as enough audio for it is
+    /// queued.
+    fn is_cycle_ready(&self) -> bool {
+        self.is_full() && self.state.armed.swap(false, Ordering::Relaxed)
+    }
+}
+
+/// The end of the queue used by the audio thread.
+struct QueueReceiver {
+    consumer: crate::ring::Consumer,
+    state: Arc<QueueState>,
+}
+
+/// returns the time of CLOCK_MONOTONIC, which pipewire uses for its clocks.
+fn monotonic_nsec() -> u64 {
+    let mut ts = libc::timespec {
+        tv_sec: 0,
+        tv_nsec: 0,
+    };
+    // SAFETY: `ts` is valid for writes
+    unsafe { libc::clock_gettime(libc::CLOCK_MONOTONIC, &mut ts) };
+    ts.tv_sec as u64 * 1_000_000_000 + ts.tv_nsec as u64
+}
 
 struct UserData {
-    unused_buffers_sender: crossbeam::channel::Sender<Box<crate::AudioFrame>>,
-    ready_buffers_receiver: crossbeam::channel::Receiver<Box<crate::AudioFrame>>,
-    stride: usize,
+    queue: QueueReceiver,
+    /// the jitter buffer latency, in seconds
+    latency: f64,
+    stride: Arc<AtomicUsize>,
+    rate: Arc<AtomicU32>,
+    /// the raw `spa::param::audio::AudioFormat`
+    format: Arc<AtomicU32>,
+    /// whether the jitter buffer is filled up before playback continues
+    prefilling: bool,
+    /// whether the end of the previous cycle was concealed
+    concealed: bool,
+    /// the end of the audio that was read last, which is repeated on underruns
+    last: Vec<u8>,
+    /// the bits of the f64 sent with `Command::ClockDrift`
+    drift: Arc<AtomicU64>,
+    rate_match: Option<NonNull<spa::sys::spa_io_rate_match>>,
+    position: Option<NonNull<spa::sys::spa_io_position>>,
+    controller: crate::drift::RateController,
+    /// position of the graph clock while driving it
+    clock_position: u64,
+}
+
+impl UserData {
+    /// returns the number of bytes that are buffered.
+    fn level(&self) -> usize {
+        self.queue.consumer.len()
+    }
+
+    /// returns the number of bytes the jitter buffer holds on top of a cycle.
+    fn latency_bytes(&self) -> usize {
+        let stride = self.stride.load(Ordering::Relaxed);
+        let rate = f64::from(self.rate.load(Ordering::Relaxed));
+        (self.latency * rate) as usize * stride
+    }
+
+    /// returns the number of frames the resampler asks for, if pipewire
+    /// matches the rate of the stream to the graph, or the number of frames
+    /// of the current graph cycle otherwise.
+    fn requested(&self, driving: bool) -> Option<usize> {
+        let frames = if let Some(rate_match) = self.rate_match
+            && !driving
+        {
+            // SAFETY: pipewire keeps the area alive until it's changed again
+            u64::from(unsafe { rate_match.as_ref() }.size)
+        } else {
+            // SAFETY: pipewire keeps the area alive until it's changed again
+            let clock = unsafe { self.position?.as_ref() }.clock;
+            // the graph might run at a different rate than the stream
+            let rate = u64::from(self.rate.load(Ordering::Relaxed));
+            match clock.rate.denom {
+                0 => clock.duration,
+                denom => {
+                    (clock.duration * rate * u64::from(clock.rate.num)).div_ceil(u64::from(denom))
+                }
+            }
+        };
+
+        usize::try_from(frames).ok().filter(|v| *v > 0)
+    }
+
+    /// copies buffered audio to `slice` and returns the number of bytes written.
+    fn read(&mut self, slice: &mut [u8]) -> usize {
+        let written = self.queue.consumer.pop(slice);
+
+        if written > 0 {
+            let stride = self.stride.load(Ordering::Relaxed);
+            let len = written.min(self.last.capacity());
+            let len = len - len % stride;
+            self.last.clear();
+            self.last.extend_from_slice(&slice[written - len..written]);
+        }
+
+        written
+    }
+
+    /// fills a cycle of `len` bytes from the jitter buffer and returns the
+    /// number of bytes that had to be concealed.
+    ///
+    /// After an underrun, the buffer is filled up to the target latency again
+    /// before playback continues.
+    fn fill(&mut self, slice: &mut [u8]) -> usize {
+        if self.prefilling && self.level() >= slice.len() + self.latency_bytes() {
+            self.prefilling = false;
+        }
+
+        let written = if self.prefilling { 0 } else { self.read(slice) };
+        if written == slice.len() {
+            self.concealed = false;
+            return 0;
+        }
+
+        if !self.prefilling {
+            self.queue.state.underruns.fetch_add(1, Ordering::Relaxed);
+            self.prefilling = true;
+        }
+
+        // repeating the end once hides short gaps, repeating it more often
+        // would cause a buzz
+        let gap = &mut slice[written..];
+        let repeat = if self.concealed {
+            0
+        } else {
+            self.last.len().min(gap.len())
+        };
+        gap[..repeat].copy_from_slice(&self.last[..repeat]);
+
+        let format = spa::param::audio::AudioFormat::from_raw(self.format.load(Ordering::Relaxed));
+        let silence = get_silence(format);
+        for (index, byte) in gap[repeat..].iter_mut().enumerate() {
+            *byte = silence[index % silence.len()];
+        }
+
+        self.concealed = true;
+        self.queue
+            .state
+            .concealed
+            .fetch_add(gap.len() as u64, Ordering::Relaxed);
+        gap.len()
+    }
+
+    /// adjusts the resampler, so it consumes the audio as fast as the host
+    /// sends it.
+    ///
+    /// `level` is the number of bytes that were buffered before `requested`
+    /// frames were read.
+    fn update_rate(&mut self, level: usize, requested: usize, underrun: bool) {
+        let Some(mut rate_match) = self.rate_match else {
+            return;
+        };
+
+        let stride = self.stride.load(Ordering::Relaxed);
+        let rate = f64::from(self.rate.load(Ordering::Relaxed));
+        let buffered = (level / stride) as f64 / rate;
+        let cycle = requested as f64 / rate;
+
+        if underrun {
+            self.controller.reset();
+        }
+        let correction = self
+            .controller
+            .update(buffered - cycle - self.latency, cycle);
+
+        let drift = f64::from_bits(self.drift.load(Ordering::Relaxed));
+        // SAFETY: pipewire keeps the area alive until it's changed again
+        let graph = self
+            .position
+            .map(|p| unsafe { p.as_ref() }.clock.rate_diff)
+            .filter(|v| *v > 0.0)
+            .unwrap_or(1.0);
+
+        // SAFETY: pipewire keeps the area alive until it's changed again
+        let rate_match = unsafe { rate_match.as_mut() };
+        // a rate below 1 makes the resampler consume the audio faster
+        rate_match.rate = graph / (drift * correction);
+        rate_match.flags |= spa::sys::SPA_IO_RATE_MATCH_FLAG_ACTIVE;
+    }
+
+    /// advances the graph clock by one cycle, which is started whenever
+    /// the host has sent enough audio for it.
+    fn update_clock(&mut self) {
+        let Some(mut position) = self.position else {
+            return;
+        };
+
+        // SAFETY: pipewire keeps the area alive until it's changed again
+        let clock = &mut unsafe { position.as_mut() }.clock;
+        let drift = f64::from_bits(self.drift.load(Ordering::Relaxed));
+        let duration = match clock.rate.denom {
+            0 => 0.0,
+            denom => clock.duration as f64 * f64::from(clock.rate.num) / f64::from(denom),
+        };
+
+        clock.nsec = monotonic_nsec();
+        clock.next_nsec = clock.nsec + (duration * 1e9 / drift) as u64;
+        clock.position = self.clock_position;
+        clock.rate_diff = drift;
+        self.clock_position += clock.duration;
+    }
 }
 
 fn get_channel_size(format: spa::param::audio::AudioFormat) -> anyhow::Result<usize> {
@@ -43,106 +720,238 @@ fn get_channel_size(format: spa::param::audio::AudioFormat) -> anyhow::Result<us
     })
 }
 
-pub fn run(
-    cli: &crate::Cli,
-    unused_buffers_sender: crossbeam::channel::Sender<Box<crate::AudioFrame>>,
-    ready_buffers_receiver: crossbeam::channel::Receiver<Box<crate::AudioFrame>>,
+/// returns one sample of silence, which isn't zero for unsigned formats.
+fn get_silence(format: spa::param::audio::AudioFormat) -> &'static [u8] {
+    match format {
+        spa::param::audio::AudioFormat::U8 => &[0x80],
+        spa::param::audio::AudioFormat::U16LE => &[0x00, 0x80],
+        spa::param::audio::AudioFormat::U16BE => &[0x80, 0x00],
+        spa::param::audio::AudioFormat::U24_32LE => &[0x00, 0x00, 0x80, 0x00],
+        spa::param::audio::AudioFormat::U24_32BE => &[0x00, 0x80, 0x00, 0x00],
+        spa::param::audio::AudioFormat::U32LE => &[0x00, 0x00, 0x00, 0x80],
+        spa::param::audio::AudioFormat::U32BE => &[0x80, 0x00, 0x00, 0x00],
+        spa::param::audio::AudioFormat::U24LE => &[0x00, 0x00, 0x80],
+        spa::param::audio::AudioFormat::U24BE => &[0x80, 0x00, 0x00],
+        spa::param::audio::AudioFormat::U20LE => &[0x00, 0x00, 0x08],
+        spa::param::audio::AudioFormat::U20BE => &[0x08, 0x00, 0x00],
+        spa::param::audio::AudioFormat::U18LE => &[0x00, 0x00, 0x02],
+        spa::param::audio::AudioFormat::U18BE => &[0x02, 0x00, 0x00],
+        _ => &[0x00],
+    }
+}
+
+/// runs the stream of a `PipeWireSink`, and sends the result of its setup to
+/// `started` before it starts processing.
+fn run(
+    name: String,
+    format: StreamFormat,
+    active: bool,
+    options: Options,
+    commands: pipewire::channel::Receiver<Command>,
+    queue: QueueReceiver,
+    started: &std::sync::mpsc::SyncSender<anyhow::Result<()>>,
 ) -> anyhow::Result<()> {
-    let channel_size = get_channel_size(cli.format)?;
+    let stride = Arc::new(AtomicUsize::new(format.stride()?));
+    let rate = Arc::new(AtomicU32::new(format.rate));
+    let raw_format = Arc::new(AtomicU32::new(format.format.as_raw()));
+    let drift = Arc::new(AtomicU64::new(1.0f64.to_bits()));
 
     let mainloop = pipewire::main_loop::MainLoop::new(None)?;
     let context = pipewire::context::Context::new(&mainloop)?;
     let core = context.connect(None)?;
-    let properties = pipewire::properties::properties! {
+    let mut properties = pipewire::properties::properties! {
         *pipewire::keys::NODE_VIRTUAL => "true",
         *pipewire::keys::MEDIA_CLASS => "Audio/Source",
-        *pipewire::keys::NODE_NAME => "USB Audio Sniffer",
+        *pipewire::keys::NODE_NAME => name.as_str(),
     };
-    let stream = pipewire::stream::Stream::new(&core, "usb-sniffer", properties)?;
+    if options.driver {
+        properties.insert(*pipewire::keys::PRIORITY_DRIVER, DRIVER_PRIORITY);
+    }
+    let stream = Rc::new(pipewire::stream::Stream::new(
+        &core,
+        "usb-sniffer",
+        properties,
+    )?);
 
+    let state = queue.state.clone();
     let data = UserData {
-        unused_buffers_sender,
-        ready_buffers_receiver,
-        stride: channel_size * cli.channels.len(),
+        queue,
+        latency: options.latency.as_secs_f64(),
+        stride: stride.clone(),
+        rate: rate.clone(),
+        format: raw_format.clone(),
+        prefilling: true,
+        concealed: false,
+        last: Vec::with_capacity(LAST_SIZE),
+        drift: drift.clone(),
+        rate_match: None,
+        position: None,
+        controller: crate::drift::RateController::default(),
+        clock_position: 0,
     };
 
     let _listener = stream
         .add_local_listener_with_user_data(data)
+        .io_changed(|_stream, userdata, id, area, _size| match id {
+            spa::sys::SPA_IO_RateMatch => userdata.rate_match = NonNull::new(area.cast()),
+            spa::sys::SPA_IO_Position => userdata.position = NonNull::new(area.cast()),
+            _ => (),
+        })
         .process(|stream, userdata| match stream.dequeue_buffer() {
-            None => println!("out of buffers"),
+            None => {
+                userdata
+                    .queue
+                    .state
+                    .missing_buffers
+                    .fetch_add(1, Ordering::Relaxed);
+            }
             Some(mut buffer) => {
+                if userdata.queue.state.flush.swap(false, Ordering::Relaxed) {
+                    userdata.queue.consumer.clear();
+                }
+
+                let stride = userdata.stride.load(Ordering::Relaxed);
+                let driving = stream.is_driving();
+                if driving {
+                    userdata.update_clock();
+                }
+
+                let requested = userdata.requested(driving);
+                let level = userdata.level();
                 let datas = buffer.datas_mut();
                 let data = &mut datas[0];
-                let n_frames = if let Some(mut slice) = data.data() {
-                    let mut total_frames = 0;
-
-                    while slice.len() > crate::sniffer::MAX_DATA_SIZE {
-                        if let Ok(frame) = userdata.ready_buffers_receiver.try_recv() {
-                            let num_frames_pipewire = slice.len() / userdata.stride;
-                            let num_frames_buffer = frame.slice().len() / userdata.stride;
-                            let num_frames_common = num_frames_buffer.min(num_frames_pipewire);
+                let n_frames = if let Some(slice) = data.data() {
+                    match requested {
+                        Some(requested) => {
+                            let len = (requested * stride).min(slice.len());
+                            let len = len - len % stride;
+                            let concealed = userdata.fill(&mut slice[..len]);
 
-                            if num_frames_common < num_frames_buffer {
-                                log::warn!("BUG: pipewire buffer is to small, partial drop");
+                            if !driving {
+                                userdata.update_rate(level, requested, concealed > 0);
                             }
 
-                            let slice_len = num_frames_common * userdata.stride;
-                            slice[0..slice_len].copy_from_slice(&frame.slice()[0..slice_len]);
-
-                            userdata.unused_buffers_sender.send(frame).unwrap();
-                            total_frames += num_frames_common;
-                            slice = &mut slice[slice_len..];
-                        } else {
-                            break;
+                            len / stride
+                        }
+                        None => {
+                            // without knowing the size of the cycle, pass on
+                            // whatever there is
+                            let len = slice.len() - slice.len() % stride;
+                            userdata.read(&mut slice[..len]) / stride
                         }
                     }
-
-                    total_frames
                 } else {
                     0
                 };
+
+                // assume that the next cycle has the same size
+                if let Some(requested) = requested {
+                    let cycle = requested * stride + userdata.latency_bytes();
+                    userdata.queue.state.cycle.store(cycle, Ordering::Relaxed);
+                }
+                userdata.queue.state.armed.store(true, Ordering::Relaxed);
                 let chunk = data.chunk_mut();
                 *chunk.offset_mut() = 0;
-                *chunk.stride_mut() = userdata.stride as _;
-                *chunk.size_mut() = (userdata.stride * n_frames) as _;
+                *chunk.stride_mut() = stride as _;
+                *chunk.size_mut() = (stride * n_frames) as _;
             }
         })
         .register()?;
 
-    let mut audio_info = spa::param::audio::AudioInfoRaw::new();
-    audio_info.set_format(cli.format);
-    audio_info.set_rate(cli.rate);
-    audio_info.set_channels(cli.channels.len().try_into().unwrap());
+    let values = format.to_params(&options)?;
+    let mut params = values
+        .each_ref()
+        .map(|v| spa::pod::Pod::from_bytes(v).unwrap());
 
-    let mut position = [0; spa::param::audio::MAX_CHANNELS];
-    for (index, channel) in cli.channels.iter().enumerate() {
-        *(position.get_mut(index).context("too many channels")?) = *channel;
+    let mut flags = pipewire::stream::StreamFlags::AUTOCONNECT
+        | pipewire::stream::StreamFlags::MAP_BUFFERS
+        | pipewire::stream::StreamFlags::RT_PROCESS;
+    if !active {
+        flags |= pipewire::stream::StreamFlags::INACTIVE;
+    }
+    if options.driver {
+        flags |= pipewire::stream::StreamFlags::DRIVER;
     }
-    audio_info.set_position(position);
 
-    let values: Vec<u8> = spa::pod::serialize::PodSerializer::serialize(
-        std::io::Cursor::new(Vec::new()),
-        &spa::pod::Value::Object(spa::pod::Object {
-            type_: spa::sys::SPA_TYPE_OBJECT_Format,
-            id: spa::sys::SPA_PARAM_EnumFormat,
-            properties: audio_info.into(),
-        }),
-    )
-    .unwrap()
-    .0
-    .into_inner();
+    stream.connect(spa::utils::Direction::Output, None, flags, &mut params)?;
+
+    let current_format = RefCell::new(format);
+    let stream2 = stream.clone();
+    let _commands = commands.attach(mainloop.loop_(), move |command| {
+        if !matches!(command, Command::Trigger) {
+            log::debug!("audio command: {command:?}");
+        }
+
+        match command {
+            Command::SetActive(active) => {
+                if !active {
+                    state.flush.store(true, Ordering::Relaxed);
+                }
+
+                if let Err(e) = stream2.set_active(active) {
+                    log::error!("failed to set stream active={active}: {e}");
+                }
+            }
+            Command::SetFormat(format) => {
+                if format == *current_format.borrow() {
+                    return;
+                }
+
+                let new_stride = match format.stride() {
+                    Ok(v) => v,
+                    Err(e) => {
+                        log::error!("unsupported format {format:?}: {e}");
+                        return;
+                    }
+                };
+                let values = match format.to_params(&options) {
+                    Ok(v) => v,
+                    Err(e) => {
+                        log::error!("failed to serialize format {format:?}: {e}");
+                        return;
+                    }
+                };
 
-    let mut params = [spa::pod::Pod::from_bytes(&values).unwrap()];
+                let mut params = values
+                    .each_ref()
+                    .map(|v| spa::pod::Pod::from_bytes(v).unwrap());
+                if let Err(e) = stream2.update_params(&mut params) {
+                    log::error!("failed to update stream format: {e}");
+                    return;
+                }
 
-    stream.connect(
-        spa::utils::Direction::Output,
-        None,
-        pipewire::stream::StreamFlags::AUTOCONNECT
-            | pipewire::stream::StreamFlags::MAP_BUFFERS
-            | pipewire::stream::StreamFlags::RT_PROCESS,
-        &mut params,
-    )?;
+                // frames of the old format would be played at the wrong speed
+                state.flush.store(true, Ordering::Relaxed);
+                stride.store(new_stride, Ordering::Relaxed);
+                rate.store(format.rate, Ordering::Relaxed);
+                raw_format.store(format.format.as_raw(), Ordering::Relaxed);
+
+                log::info!("audio format: {format:?}");
+                *current_format.borrow_mut() = format;
+            }
+            Command::ClockDrift(ratio) => {
+                drift.store(ratio.to_bits(), Ordering::Relaxed);
+            }
+            Command::SetVolume(volume) => {
+                let mute = if volume.mute { 1.0 } else { 0.0 };
+                if let Err(e) = stream2
+                    .set_control(spa::sys::SPA_PROP_channelVolumes, &volume.channels)
+                    .and_then(|()| stream2.set_control(spa::sys::SPA_PROP_mute, &[mute]))
+                {
+                    log::error!("failed to set stream volume: {e}");
+                }
+            }
+            Command::Trigger => {
+                if stream2.is_driving()
+                    && let Err(e) = stream2.trigger_process()
+                {
+                    log::error!("failed to trigger graph cycle: {e}");
+                }
+            }
+        }
+    });
 
+    let _ = started.send(Ok(()));
     mainloop.run();
     Ok(())
 }
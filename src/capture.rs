@@ -0,0 +1,346 @@
+use crate::sniffer::Sniffer;
+use crate::{audio, control, device, drift, pcap, record, sniffer, stream, usb};
+use anyhow::Context as _;
+
+/// minimum time between two reports of damaged packets, in nanoseconds
+const ERROR_REPORT_INTERVAL: u64 = 10_000_000_000;
+/// time between two updates of the clock drift, in nanoseconds
+const DRIFT_UPDATE_INTERVAL: u64 = 10_000_000_000;
+/// time between two reports of the state of the audio outputs, in nanoseconds
+const STATUS_REPORT_INTERVAL: u64 = 10_000_000_000;
+
+/// The settings of the audio of one direction.
+#[derive(Debug)]
+pub struct StreamConfig {
+    /// name of the pipewire node
+    pub name: String,
+    pub overrides: stream::FormatOverride,
+    /// only capture the audio of this endpoint number
+    pub endpoint: Option<u8>,
+    /// where the audio goes, or None if it isn't captured
+    pub output: Option<audio::Output>,
+}
+
+impl StreamConfig {
+    fn stream(
+        self,
+        token_pid: usb::Pid,
+        filter: device::DeviceFilter,
+        options: audio::Options,
+    ) -> anyhow::Result<stream::AudioStream> {
+        let sink = self
+            .output
+            .map(|v| v.sink(&self.name, options))
+            .transpose()?;
+
+        Ok(stream::AudioStream::new(
+            self.name,
+            self.overrides,
+            stream::AudioReceiver::new(token_pid, filter.address, self.endpoint),
+            sink,
+        ))
+    }
+}
+
+/// The settings of a capture.
+#[derive(Debug)]
+pub struct Config {
+    /// serial number or path of the sniffer to use
+    pub device: Option<String>,
+    /// speed of the captured bus, detected from the line state if None
+    pub speed: Option<sniffer::CaptureSpeed>,
+    pub filter: device::DeviceFilter,
+    pub speaker: StreamConfig,
+    pub microphone: StreamConfig,
+    pub audio: audio::Options,
+    /// read the sniffer data from this file instead of the device
+    pub replay: Option<std::path::PathBuf>,
+    /// write the raw sniffer data to this file
+    pub record: Option<std::path::PathBuf>,
+    /// write all captured USB packets to this pcapng file
+    pub pcap: Option<std::path::PathBuf>,
+}
+
+/// starts capturing with a newly connected sniffer.
+async fn open_sniffer(
+    device: Option<&str>,
+    speed: sniffer::CaptureSpeed,
+    record: Option<&std::fs::File>,
+) -> anyhow::Result<(Box<dyn tokio::io::AsyncRead + Unpin>, sniffer::Control)> {
+    let mut sniffer = Sniffer::new(device)
+        .await
+        .context("failed to create sniffer")?;
+    sniffer.start(speed).await?;
+    let control = sniffer.control();
+
+    let reader: Box<dyn tokio::io::AsyncRead + Unpin> = match record {
+        Some(file) => {
+            // the recording continues where the previous sniffer stopped
+            let file = file.try_clone().context("failed to clone record file")?;
+            Box::new(record::Recorder::new(sniffer.reader(), file))
+        }
+        None => Box::new(sniffer.reader()),
+    };
+
+    Ok((reader, control))
+}
+
+/// waits for the sniffer to be plugged in again and restarts the capture.
+async fn reopen_sniffer(
+    device: Option<&str>,
+    speed: sniffer::CaptureSpeed,
+    record: Option<&std::fs::File>,
+) -> anyhow::Result<(Box<dyn tokio::io::AsyncRead + Unpin>, sniffer::Control)> {
+    loop {
+        sniffer::wait_for_device(device).await?;
+
+        match open_sniffer(device, speed, record).await {
+            Ok(v) => return Ok(v),
+            Err(e) => {
+                // udev might not have set up the permissions yet
+                log::warn!("failed to reopen sniffer: {e:#}");
+                tokio::time::sleep(core::time::Duration::from_secs(1)).await;
+            }
+        }
+    }
+}
+
+/// waits for SIGINT or SIGTERM.
+async fn stop_requested(
+    interrupt: &mut tokio::signal::unix::Signal,
+    terminate: &mut tokio::signal::unix::Signal,
+) {
+    let name = tokio::select! {
+        _ = interrupt.recv() => "SIGINT",
+        _ = terminate.recv() => "SIGTERM",
+    };
+    log::info!("received {name}, stopping the capture");
+}
+
+/// captures the audio of the sniffer, or of a replayed recording, until it
+/// ends.
+pub async fn run(config: Config) -> anyhow::Result<()> {
+    anyhow::ensure!(
+        !matches!(
+            (&config.speaker.output, &config.microphone.output),
+            (Some(audio::Output::Stdout), Some(audio::Output::Stdout))
+        ),
+        "only one stream can be written to stdout"
+    );
+
+    let mut transactions = usb::TransactionAssembler::default();
+    let mut control_receiver = control::ControlReceiver::default();
+    let mut stream_control = stream::StreamControl::new(
+        config.filter,
+        config
+            .speaker
+            .stream(usb::Pid::Out, config.filter, config.audio)?,
+        config
+            .microphone
+            .stream(usb::Pid::In, config.filter, config.audio)?,
+    );
+
+    if !stream_control.is_running() {
+        log::info!("waiting for the host to read the configuration descriptor");
+    }
+
+    let record_file = match &config.record {
+        Some(path) => Some(
+            std::fs::File::create(path)
+                .with_context(|| format!("failed to create {}", path.display()))?,
+        ),
+        None => None,
+    };
+
+    let mut speed = config.speed.unwrap_or(sniffer::CaptureSpeed::FullSpeed);
+    let mut sniffer_control = None;
+    let reader: Box<dyn tokio::io::AsyncRead + Unpin> = match &config.replay {
+        Some(path) => {
+            let file = tokio::fs::File::open(path)
+                .await
+                .with_context(|| format!("failed to open {}", path.display()))?;
+            Box::new(tokio::io::BufReader::new(file))
+        }
+        None => {
+            let (reader, control) =
+                open_sniffer(config.device.as_deref(), speed, record_file.as_ref()).await?;
+            sniffer_control = Some(control);
+            reader
+        }
+    };
+
+    let mut pcap = match &config.pcap {
+        Some(path) => {
+            let file = std::fs::File::create(path)
+                .with_context(|| format!("failed to create {}", path.display()))?;
+            Some(pcap::PcapWriter::new(file).context("failed to write pcap header")?)
+        }
+        None => None,
+    };
+
+    let mut speed_detector = config
+        .speed
+        .is_none()
+        .then(|| sniffer::SpeedDetector::new(speed));
+    let mut status = None;
+    let mut bus_reset = false;
+    let mut errors = sniffer::ErrorCounters::default();
+    let mut next_error_report = 0;
+    // the receive times of replayed records have nothing to do with the host
+    let mut drift_estimator = config.replay.is_none().then(drift::DriftEstimator::default);
+    let mut next_drift_update = 0;
+    let mut next_status_report = 0;
+    // created once, so signals between two reads aren't lost
+    let mut interrupt = tokio::signal::unix::signal(tokio::signal::unix::SignalKind::interrupt())
+        .context("failed to handle SIGINT")?;
+    let mut terminate = tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate())
+        .context("failed to handle SIGTERM")?;
+    let mut events = sniffer::EventReader::new(reader);
+    loop {
+        let next = tokio::select! {
+            v = events.next() => v,
+            // returning drops the outputs, which finishes the files
+            () = stop_requested(&mut interrupt, &mut terminate) => return Ok(()),
+        };
+        let event = match next {
+            Ok(v) => v,
+            Err(e) if e.kind() == std::io::ErrorKind::UnexpectedEof && config.replay.is_some() => {
+                log::info!("end of replay");
+                log::info!(
+                    "capture errors: {errors:?}, concealed audio packets: {}",
+                    stream_control.concealed()
+                );
+                return Ok(());
+            }
+            Err(e) if config.replay.is_none() => {
+                log::error!("lost the sniffer: {e}, waiting for it to be reconnected");
+                let (reader, control) = tokio::select! {
+                    v = reopen_sniffer(config.device.as_deref(), speed, record_file.as_ref()) => v?,
+                    () = stop_requested(&mut interrupt, &mut terminate) => return Ok(()),
+                };
+                log::info!("sniffer reconnected");
+
+                // the headset might have been reconnected in the meantime
+                events.reset(reader);
+                sniffer_control = Some(control);
+                if speed_detector.is_some() {
+                    speed_detector = Some(sniffer::SpeedDetector::new(speed));
+                }
+                status = None;
+                next_error_report = 0;
+                drift_estimator = Some(drift::DriftEstimator::default());
+                next_drift_update = 0;
+                next_status_report = 0;
+                transactions = usb::TransactionAssembler::default();
+                control_receiver = control::ControlReceiver::default();
+                stream_control.bus_reset();
+                continue;
+            }
+            Err(e) => return Err(e.into()),
+        };
+        let timestamp = event.timestamp();
+
+        match event {
+            sniffer::SnifferEvent::Data(packet) => {
+                let usb_data = &packet.data[..];
+                if usb_data.is_empty() {
+                    continue;
+                }
+
+                if let Some(writer) = &mut pcap
+                    && let Err(e) = writer.write_packet(timestamp, usb_data)
+                {
+                    log::error!("failed to write pcap, stop writing: {e}");
+                    pcap = None;
+                }
+
+                if timestamp >= next_status_report {
+                    stream_control.report();
+                    next_status_report = timestamp + STATUS_REPORT_INTERVAL;
+                }
+
+                let valid = errors.check(&packet);
+                if !valid && timestamp >= next_error_report {
+                    log::warn!(
+                        "capture errors: {errors:?}, concealed audio packets: {}",
+                        stream_control.concealed()
+                    );
+                    next_error_report = timestamp + ERROR_REPORT_INTERVAL;
+                }
+
+                let usb_packet = usb::Packet::parse(usb_data);
+                if let Some(transaction) = transactions.packet_received(&usb_packet, valid) {
+                    if let Some(transfer) = control_receiver.transaction_received(transaction) {
+                        stream_control.transfer_received(&transfer);
+                    }
+                    stream_control.transaction_received(transaction);
+                }
+
+                if let usb::Packet::Sof { frame_number } = usb_packet {
+                    stream_control.sof_received();
+
+                    if valid && let Some(estimator) = &mut drift_estimator {
+                        estimator.sof_received(timestamp, frame_number, std::time::Instant::now());
+
+                        if timestamp >= next_drift_update
+                            && let Some(ratio) = estimator.ratio()
+                        {
+                            log::debug!("host clock drift: {:+.1} ppm", (ratio - 1.0) * 1e6);
+                            stream_control.set_drift(ratio);
+                            next_drift_update = timestamp + DRIFT_UPDATE_INTERVAL;
+                        }
+                    }
+                }
+            }
+            sniffer::SnifferEvent::Status(event) => {
+                let state = event.state;
+                if status != Some(state) {
+                    status = Some(state);
+
+                    if let Some(writer) = &mut pcap {
+                        writer.add_comment(&format!(
+                            "status at {:.6}s: speed={} vbus={} ls={:#x} trigger={}",
+                            timestamp as f64 / 1e9,
+                            state.speed.name(),
+                            state.vbus,
+                            state.line_state,
+                            state.trigger,
+                        ));
+                    }
+                }
+
+                // the device is enumerated again once the reset is over or
+                // VBUS is back
+                let reset = state.is_bus_reset() || !state.vbus;
+                if reset && !bus_reset {
+                    log::info!(
+                        "{}, waiting for the device to be configured again",
+                        if state.vbus {
+                            "bus reset"
+                        } else {
+                            "device disconnected"
+                        }
+                    );
+                    transactions = usb::TransactionAssembler::default();
+                    control_receiver = control::ControlReceiver::default();
+                    stream_control.bus_reset();
+                }
+                bus_reset = reset;
+
+                if let Some(detector) = &mut speed_detector
+                    && let Some(detected) = detector.status_received(&event)
+                {
+                    log::info!("detected bus speed: {detected:?}");
+                    speed = detected;
+
+                    if let Some(control) = &sniffer_control {
+                        match control.restart(speed).await {
+                            Ok(()) => events.restart(),
+                            Err(e) => log::error!("failed to change capture speed: {e:#}"),
+                        }
+                    }
+                }
+            }
+        }
+    }
+}
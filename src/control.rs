@@ -0,0 +1,568 @@
+use crate::descriptor;
+use crate::usb;
+use std::collections::HashMap;
+
+const UAC_SET_CUR: u8 = 0x01;
+const UAC_GET_CUR: u8 = 0x81;
+const UAC_EP_SAMPLING_FREQ_CONTROL: u8 = 0x01;
+const UAC_FU_MUTE_CONTROL: u8 = 0x01;
+const UAC_FU_VOLUME_CONTROL: u8 = 0x02;
+/// the volume of a feature unit that means silence
+const UAC_VOLUME_SILENCE: i16 = i16::MIN;
+
+const UAC2_CUR: u8 = 0x01;
+const UAC2_RANGE: u8 = 0x02;
+const UAC2_CS_SAM_FREQ_CONTROL: u8 = 0x01;
+
+/// The entity a sampling frequency request is addressed to.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum SamplingFrequencyTarget {
+    /// the endpoint number, including the direction bit, of a UAC1 device
+    Endpoint(u8),
+    /// the clock source entity of a UAC2 device
+    Clock(u8),
+}
+
+/// A control of a feature unit, set or read by the host.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum FeatureUnitControl {
+    Mute(bool),
+    /// in dB, negative infinity for silence
+    Volume(f32),
+}
+
+/// The recipient of a class request.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Recipient {
+    Device,
+    /// the interface number, the entity is in the high byte of the index
+    Interface(u8),
+    /// the endpoint number, including the direction bit
+    Endpoint(u8),
+    Other,
+}
+
+/// The request of a control transfer, decoded from its setup packet.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Request {
+    GetDescriptor {
+        descriptor_type: u8,
+        index: u8,
+        /// the language ID of string descriptors
+        language: u16,
+    },
+    SetAddress(u8),
+    SetConfiguration(u8),
+    SetInterface {
+        interface: u8,
+        alternate_setting: u8,
+    },
+    /// any other standard request
+    Standard(u8),
+    Class {
+        recipient: Recipient,
+        request: u8,
+        value: u16,
+        index: u16,
+    },
+    Vendor(u8),
+}
+
+#[derive(Debug)]
+pub struct ControlTransfer {
+    pub address: u8,
+    pub setup: usb::SetupPacket<[u8; 8]>,
+    pub data: Vec<u8>,
+}
+
+impl ControlTransfer {
+    pub fn request(&self) -> Request {
+        let setup = &self.setup;
+        let request = setup.request();
+
+        match setup.kind() {
+            usb::REQUEST_KIND_STANDARD => match request {
+                usb::REQUEST_GET_DESCRIPTOR if setup.device_to_host() => Request::GetDescriptor {
+                    descriptor_type: (setup.value() >> 8) as u8,
+                    index: setup.value() as u8,
+                    language: setup.index(),
+                },
+                usb::REQUEST_SET_ADDRESS => Request::SetAddress(setup.value() as u8),
+                usb::REQUEST_SET_CONFIGURATION => Request::SetConfiguration(setup.value() as u8),
+                usb::REQUEST_SET_INTERFACE => Request::SetInterface {
+                    interface: setup.index() as u8,
+                    alternate_setting: setup.value() as u8,
+                },
+                _ => Request::Standard(request),
+            },
+            usb::REQUEST_KIND_CLASS => Request::Class {
+                recipient: match setup.recipient() {
+                    usb::RECIPIENT_DEVICE => Recipient::Device,
+                    usb::RECIPIENT_INTERFACE => Recipient::Interface(setup.index() as u8),
+                    usb::RECIPIENT_ENDPOINT => Recipient::Endpoint(setup.index() as u8),
+                    _ => Recipient::Other,
+                },
+                request,
+                value: setup.value(),
+                index: setup.index(),
+            },
+            // vendor and reserved
+            _ => Request::Vendor(request),
+        }
+    }
+
+    /// returns the target and rate of a request that sets or reads the
+    /// current sampling frequency.
+    pub fn sampling_frequency(&self) -> Option<(SamplingFrequencyTarget, u32)> {
+        let control = (self.setup.value() >> 8) as u8;
+
+        match (self.setup.request_type(), self.setup.request()) {
+            (0x22, UAC_SET_CUR) | (0xa2, UAC_GET_CUR)
+                if control == UAC_EP_SAMPLING_FREQ_CONTROL =>
+            {
+                let rate = self.data.get(0..3)?;
+                Some((
+                    SamplingFrequencyTarget::Endpoint(self.setup.index() as u8),
+                    u32::from_le_bytes([rate[0], rate[1], rate[2], 0]),
+                ))
+            }
+            // UAC1 uses the same request for interface controls, so the
+            // caller has to make sure that the entity is a clock source.
+            (0x21 | 0xa1, UAC2_CUR) if control == UAC2_CS_SAM_FREQ_CONTROL => {
+                let rate = self.data.get(0..4)?;
+                Some((
+                    SamplingFrequencyTarget::Clock((self.setup.index() >> 8) as u8),
+                    u32::from_le_bytes([rate[0], rate[1], rate[2], rate[3]]),
+                ))
+            }
+            _ => None,
+        }
+    }
+
+    /// returns the feature unit, channel and value of a request that sets or
+    /// reads the current mute or volume control.
+    ///
+    /// Channel 0 is the master channel. The UAC2 request is the same as the
+    /// one for clock sources, so the caller has to make sure that the entity
+    /// is a feature unit.
+    pub fn feature_unit_control(&self) -> Option<(u8, u8, FeatureUnitControl)> {
+        match (self.setup.request_type(), self.setup.request()) {
+            (0x21, UAC_SET_CUR) | (0xa1, UAC_GET_CUR | UAC2_CUR) => (),
+            _ => return None,
+        }
+
+        let control = match (self.setup.value() >> 8) as u8 {
+            UAC_FU_MUTE_CONTROL => FeatureUnitControl::Mute(*self.data.first()? != 0),
+            UAC_FU_VOLUME_CONTROL => {
+                let volume = self.data.get(0..2)?;
+                let volume = i16::from_le_bytes([volume[0], volume[1]]);
+                FeatureUnitControl::Volume(if volume == UAC_VOLUME_SILENCE {
+                    f32::NEG_INFINITY
+                } else {
+                    f32::from(volume) / 256.0
+                })
+            }
+            _ => return None,
+        };
+
+        Some((
+            (self.setup.index() >> 8) as u8,
+            self.setup.value() as u8,
+            control,
+        ))
+    }
+
+    /// returns the clock source and its rates reported by a UAC2 RANGE request.
+    pub fn sampling_frequency_range(&self) -> Option<(u8, descriptor::SampleRates)> {
+        if self.setup.request_type() != 0xa1
+            || self.setup.request() != UAC2_RANGE
+            || (self.setup.value() >> 8) as u8 != UAC2_CS_SAM_FREQ_CONTROL
+        {
+            return None;
+        }
+
+        Some((
+            (self.setup.index() >> 8) as u8,
+            descriptor::SampleRates::parse_range(&self.data)?,
+        ))
+    }
+}
+
+/// Reassembles control transfers on endpoint 0 of all devices on the bus.
+///
+/// Data is only taken into account once the receiver acknowledged it, so
+/// retransmissions don't end up in the transfer twice.
+#[derive(Default)]
+pub struct ControlReceiver {
+    /// the transfers in progress by device address, since the host can talk
+    /// to several devices in between
+    transfers: HashMap<u8, ControlTransfer>,
+}
+
+impl ControlReceiver {
+    /// returns the transfer, if `transaction` completed its status stage.
+    ///
+    /// Damaged transactions are ignored. The receiver of the damaged packet
+    /// will have dropped it as well, so it's going to be retried.
+    pub fn transaction_received(
+        &mut self,
+        transaction: &usb::Transaction,
+    ) -> Option<ControlTransfer> {
+        let token = transaction.token;
+        if token.endpoint != 0 || transaction.damaged {
+            return None;
+        }
+
+        let acknowledged = match transaction.handshake {
+            Some(usb::Pid::Ack) => true,
+            // accepted by a High Speed device, which wants a PING before the
+            // next OUT
+            Some(usb::Pid::Nyet) => token.pid != usb::Pid::In,
+            _ => false,
+        };
+
+        match transaction.handshake {
+            Some(_) if acknowledged && transaction.data_pid.is_some() => {
+                self.transaction_completed(token.pid, token.address, &transaction.payload)
+            }
+            Some(usb::Pid::Stall) if self.transfers.remove(&token.address).is_some() => {
+                log::debug!("control transfer to {} stalled", token.address);
+                None
+            }
+            _ => None,
+        }
+    }
+
+    fn transaction_completed(
+        &mut self,
+        pid: usb::Pid,
+        address: u8,
+        payload: &[u8],
+    ) -> Option<ControlTransfer> {
+        if pid == usb::Pid::Setup {
+            let Ok(setup) = <[u8; 8]>::try_from(payload) else {
+                log::warn!("invalid setup packet size: {}", payload.len());
+                return None;
+            };
+
+            self.transfers.insert(
+                address,
+                ControlTransfer {
+                    address,
+                    setup: usb::SetupPacket(setup),
+                    data: Vec::new(),
+                },
+            );
+            return None;
+        }
+
+        let transfer = self.transfers.get_mut(&address)?;
+        let data_stage_pid = if transfer.setup.device_to_host() {
+            usb::Pid::In
+        } else {
+            usb::Pid::Out
+        };
+
+        if pid == data_stage_pid && transfer.setup.length() > 0 {
+            transfer.data.extend_from_slice(payload);
+            None
+        } else {
+            self.transfers.remove(&address)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const GET_CONFIGURATION: [u8; 8] = [0x80, 0x06, 0x00, 0x02, 0x00, 0x00, 0x09, 0x00];
+    /// UAC1 SET_CUR(SAMPLING_FREQ_CONTROL) of endpoint 1 to 48 kHz
+    const SET_SAMPLING_FREQUENCY: [u8; 8] = [0x22, 0x01, 0x00, 0x01, 0x01, 0x00, 0x03, 0x00];
+
+    fn transaction(
+        token_pid: usb::Pid,
+        data: Option<&[u8]>,
+        handshake: usb::Pid,
+    ) -> usb::Transaction {
+        usb::Transaction {
+            split: None,
+            token: usb::Token {
+                pid: token_pid,
+                address: 3,
+                endpoint: 0,
+            },
+            data_pid: data.map(|_| usb::Pid::Data1),
+            payload: data.unwrap_or_default().to_vec(),
+            handshake: Some(handshake),
+            damaged: false,
+        }
+    }
+
+    /// returns `transaction` sent to `address` instead.
+    fn to(address: u8, mut transaction: usb::Transaction) -> usb::Transaction {
+        transaction.token.address = address;
+        transaction
+    }
+
+    fn receive(transactions: &[usb::Transaction]) -> Vec<ControlTransfer> {
+        let mut receiver = ControlReceiver::default();
+        transactions
+            .iter()
+            .filter_map(|t| receiver.transaction_received(t))
+            .collect()
+    }
+
+    #[test]
+    fn device_to_host() {
+        let transfers = receive(&[
+            transaction(usb::Pid::Setup, Some(&GET_CONFIGURATION), usb::Pid::Ack),
+            transaction(usb::Pid::In, None, usb::Pid::Nak),
+            transaction(usb::Pid::In, Some(&[9, 2, 0x64, 0]), usb::Pid::Ack),
+            transaction(usb::Pid::In, Some(&[1, 1, 0, 0x80, 0x32]), usb::Pid::Ack),
+            transaction(usb::Pid::Out, Some(&[]), usb::Pid::Ack),
+        ]);
+
+        assert_eq!(transfers.len(), 1);
+        assert_eq!(transfers[0].address, 3);
+        assert_eq!(transfers[0].data, [9, 2, 0x64, 0, 1, 1, 0, 0x80, 0x32]);
+        assert_eq!(
+            transfers[0].request(),
+            Request::GetDescriptor {
+                descriptor_type: usb::DESCRIPTOR_TYPE_CONFIGURATION,
+                index: 0,
+                language: 0,
+            }
+        );
+    }
+
+    #[test]
+    fn retried_data_is_taken_once() {
+        let mut damaged = transaction(usb::Pid::In, Some(&[9, 2, 0x64, 0]), usb::Pid::Ack);
+        damaged.damaged = true;
+
+        let transfers = receive(&[
+            transaction(usb::Pid::Setup, Some(&GET_CONFIGURATION), usb::Pid::Ack),
+            damaged,
+            transaction(usb::Pid::In, Some(&[9, 2, 0x64, 0]), usb::Pid::Nak),
+            transaction(usb::Pid::In, Some(&[9, 2, 0x64, 0]), usb::Pid::Ack),
+            transaction(usb::Pid::Out, Some(&[]), usb::Pid::Ack),
+        ]);
+
+        assert_eq!(transfers.len(), 1);
+        assert_eq!(transfers[0].data, [9, 2, 0x64, 0]);
+    }
+
+    #[test]
+    fn interleaved_devices() {
+        let transfers = receive(&[
+            transaction(usb::Pid::Setup, Some(&GET_CONFIGURATION), usb::Pid::Ack),
+            transaction(usb::Pid::In, Some(&[9, 2, 0x64, 0]), usb::Pid::Ack),
+            // e.g. the host polls a hub in between
+            to(
+                1,
+                transaction(
+                    usb::Pid::Setup,
+                    Some(&[0xa0, 0x00, 0x00, 0x00, 0x00, 0x00, 0x04, 0x00]),
+                    usb::Pid::Ack,
+                ),
+            ),
+            to(
+                1,
+                transaction(usb::Pid::In, Some(&[1, 0, 0, 0]), usb::Pid::Ack),
+            ),
+            transaction(usb::Pid::In, Some(&[1, 1, 0, 0x80, 0x32]), usb::Pid::Ack),
+            to(1, transaction(usb::Pid::Out, Some(&[]), usb::Pid::Ack)),
+            // a stall of the hub doesn't end the transfer of the headset
+            to(
+                1,
+                transaction(
+                    usb::Pid::Setup,
+                    Some(&[0xa3, 0x00, 0x00, 0x00, 0x01, 0x00, 0x04, 0x00]),
+                    usb::Pid::Ack,
+                ),
+            ),
+            to(1, transaction(usb::Pid::In, None, usb::Pid::Stall)),
+            transaction(usb::Pid::Out, Some(&[]), usb::Pid::Ack),
+        ]);
+
+        assert_eq!(transfers.len(), 2);
+        assert_eq!(transfers[0].address, 1);
+        assert_eq!(transfers[0].data, [1, 0, 0, 0]);
+        assert_eq!(transfers[1].address, 3);
+        assert_eq!(transfers[1].data, [9, 2, 0x64, 0, 1, 1, 0, 0x80, 0x32]);
+    }
+
+    #[test]
+    fn stall() {
+        let transfers = receive(&[
+            transaction(usb::Pid::Setup, Some(&GET_CONFIGURATION), usb::Pid::Ack),
+            transaction(usb::Pid::In, None, usb::Pid::Stall),
+            transaction(usb::Pid::Out, Some(&[]), usb::Pid::Ack),
+        ]);
+
+        assert!(transfers.is_empty());
+    }
+
+    #[test]
+    fn nyet() {
+        let transfers = receive(&[
+            transaction(
+                usb::Pid::Setup,
+                Some(&SET_SAMPLING_FREQUENCY),
+                usb::Pid::Ack,
+            ),
+            transaction(usb::Pid::Out, Some(&[0x80, 0xbb, 0x00]), usb::Pid::Nyet),
+            transaction(usb::Pid::In, Some(&[]), usb::Pid::Ack),
+        ]);
+
+        assert_eq!(transfers.len(), 1);
+        assert_eq!(
+            transfers[0].sampling_frequency(),
+            Some((SamplingFrequencyTarget::Endpoint(1), 48000))
+        );
+    }
+
+    #[test]
+    fn nyet_is_no_acknowledgement_of_in() {
+        let transfers = receive(&[
+            transaction(usb::Pid::Setup, Some(&GET_CONFIGURATION), usb::Pid::Ack),
+            transaction(usb::Pid::In, Some(&[9, 2, 0x64, 0]), usb::Pid::Nyet),
+            transaction(usb::Pid::Out, Some(&[]), usb::Pid::Ack),
+        ]);
+
+        assert_eq!(transfers.len(), 1);
+        assert!(transfers[0].data.is_empty());
+    }
+
+    /// returns a control transfer to a feature unit of interface 0.
+    fn feature_unit(
+        request_type: u8,
+        request: u8,
+        control: u8,
+        channel: u8,
+        unit: u8,
+        data: &[u8],
+    ) -> ControlTransfer {
+        ControlTransfer {
+            address: 3,
+            setup: usb::SetupPacket([
+                request_type,
+                request,
+                channel,
+                control,
+                0,
+                unit,
+                data.len() as u8,
+                0,
+            ]),
+            data: data.to_vec(),
+        }
+    }
+
+    #[test]
+    fn mute() {
+        // UAC1 and UAC2 SET_CUR are the same request
+        let transfer = feature_unit(0x21, UAC_SET_CUR, UAC_FU_MUTE_CONTROL, 0, 2, &[1]);
+        assert_eq!(
+            transfer.feature_unit_control(),
+            Some((2, 0, FeatureUnitControl::Mute(true)))
+        );
+
+        let transfer = feature_unit(0x21, UAC_SET_CUR, UAC_FU_MUTE_CONTROL, 2, 5, &[0]);
+        assert_eq!(
+            transfer.feature_unit_control(),
+            Some((5, 2, FeatureUnitControl::Mute(false)))
+        );
+    }
+
+    #[test]
+    fn volume() {
+        // -6 dB on the master channel
+        let transfer = feature_unit(
+            0x21,
+            UAC_SET_CUR,
+            UAC_FU_VOLUME_CONTROL,
+            0,
+            2,
+            &[0x00, 0xfa],
+        );
+        assert_eq!(
+            transfer.feature_unit_control(),
+            Some((2, 0, FeatureUnitControl::Volume(-6.0)))
+        );
+
+        // +1.5 dB on the right channel
+        let transfer = feature_unit(
+            0x21,
+            UAC_SET_CUR,
+            UAC_FU_VOLUME_CONTROL,
+            2,
+            2,
+            &[0x80, 0x01],
+        );
+        assert_eq!(
+            transfer.feature_unit_control(),
+            Some((2, 2, FeatureUnitControl::Volume(1.5)))
+        );
+
+        let transfer = feature_unit(
+            0x21,
+            UAC_SET_CUR,
+            UAC_FU_VOLUME_CONTROL,
+            1,
+            2,
+            &[0x00, 0x80],
+        );
+        assert_eq!(
+            transfer.feature_unit_control(),
+            Some((2, 1, FeatureUnitControl::Volume(f32::NEG_INFINITY)))
+        );
+    }
+
+    #[test]
+    fn volume_read() {
+        // UAC1 GET_CUR
+        let transfer = feature_unit(
+            0xa1,
+            UAC_GET_CUR,
+            UAC_FU_VOLUME_CONTROL,
+            1,
+            2,
+            &[0x00, 0xf4],
+        );
+        assert_eq!(
+            transfer.feature_unit_control(),
+            Some((2, 1, FeatureUnitControl::Volume(-12.0)))
+        );
+
+        // UAC2 CUR
+        let transfer = feature_unit(0xa1, UAC2_CUR, UAC_FU_VOLUME_CONTROL, 1, 2, &[0x00, 0xf4]);
+        assert_eq!(
+            transfer.feature_unit_control(),
+            Some((2, 1, FeatureUnitControl::Volume(-12.0)))
+        );
+    }
+
+    #[test]
+    fn not_a_feature_unit_control() {
+        // a volume cut short by the wLength of the request
+        let transfer = feature_unit(0x21, UAC_SET_CUR, UAC_FU_VOLUME_CONTROL, 0, 2, &[0x00]);
+        assert_eq!(transfer.feature_unit_control(), None);
+
+        // the bass control
+        let transfer = feature_unit(0x21, UAC_SET_CUR, 0x03, 0, 2, &[0x00]);
+        assert_eq!(transfer.feature_unit_control(), None);
+
+        // SET_MIN
+        let transfer = feature_unit(0x21, 0x02, UAC_FU_VOLUME_CONTROL, 0, 2, &[0x00, 0xfa]);
+        assert_eq!(transfer.feature_unit_control(), None);
+
+        let transfer = ControlTransfer {
+            address: 3,
+            setup: usb::SetupPacket(SET_SAMPLING_FREQUENCY),
+            data: vec![0x80, 0xbb, 0x00],
+        };
+        assert_eq!(transfer.feature_unit_control(), None);
+    }
+}
@@ -0,0 +1,815 @@
+#[cfg(feature = "pipewire")]
+use pipewire::spa;
+
+const DESCRIPTOR_TYPE_INTERFACE: u8 = 0x04;
+const DESCRIPTOR_TYPE_ENDPOINT: u8 = 0x05;
+const DESCRIPTOR_TYPE_CS_INTERFACE: u8 = 0x24;
+
+const CLASS_AUDIO: u8 = 0x01;
+const SUBCLASS_AUDIOCONTROL: u8 = 0x01;
+const SUBCLASS_AUDIOSTREAMING: u8 = 0x02;
+
+const PROTOCOL_UAC2: u8 = 0x20;
+
+const AC_INPUT_TERMINAL: u8 = 0x02;
+const AC_OUTPUT_TERMINAL: u8 = 0x03;
+const AC_FEATURE_UNIT: u8 = 0x06;
+const AC_CLOCK_SELECTOR: u8 = 0x0b;
+const AC_CLOCK_MULTIPLIER: u8 = 0x0c;
+const AS_GENERAL: u8 = 0x01;
+const AS_FORMAT_TYPE: u8 = 0x02;
+
+const FORMAT_TYPE_I: u8 = 0x01;
+
+const FORMAT_TAG_PCM: u16 = 0x0001;
+const FORMAT_TAG_PCM8: u16 = 0x0002;
+const FORMAT_TAG_IEEE_FLOAT: u16 = 0x0003;
+
+const TRANSFER_TYPE_ISOCHRONOUS: u8 = 0x01;
+
+#[derive(Clone, Debug)]
+pub enum SampleRates {
+    Continuous(u32, u32),
+    Discrete(Vec<u32>),
+}
+
+impl SampleRates {
+    /// parses the response of a UAC2 RANGE request to a sampling frequency control.
+    pub fn parse_range(data: &[u8]) -> Option<Self> {
+        let count: usize = u16::from_le_bytes([*data.first()?, *data.get(1)?]).into();
+        let ranges: Vec<(u32, u32)> = data[2..]
+            .chunks_exact(12)
+            .take(count)
+            .map(|r| {
+                (
+                    u32::from_le_bytes([r[0], r[1], r[2], r[3]]),
+                    u32::from_le_bytes([r[4], r[5], r[6], r[7]]),
+                )
+            })
+            .collect();
+
+        if ranges.is_empty() {
+            None
+        } else if ranges.iter().all(|(min, max)| min == max) {
+            Some(Self::Discrete(
+                ranges.iter().map(|(rate, _)| *rate).collect(),
+            ))
+        } else {
+            Some(Self::Continuous(ranges[0].0, ranges[ranges.len() - 1].1))
+        }
+    }
+
+    /// returns the rate the host most likely uses, because there is no way to
+    /// know it from the descriptors alone.
+    pub fn preferred(&self) -> Option<u32> {
+        const PREFERRED_RATE: u32 = 48000;
+
+        match self {
+            Self::Continuous(min, max) => Some(if (*min..=*max).contains(&PREFERRED_RATE) {
+                PREFERRED_RATE
+            } else {
+                *max
+            }),
+            Self::Discrete(rates) => {
+                if rates.contains(&PREFERRED_RATE) {
+                    Some(PREFERRED_RATE)
+                } else {
+                    rates.first().copied()
+                }
+            }
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct InputTerminal {
+    pub id: u8,
+    pub channel_config: u32,
+    /// the clock source entity, only UAC2 devices have one
+    pub clock_source: Option<u8>,
+}
+
+#[derive(Clone, Debug)]
+pub struct OutputTerminal {
+    pub id: u8,
+    pub source_id: u8,
+    /// the clock source entity, only UAC2 devices have one
+    pub clock_source: Option<u8>,
+}
+
+#[derive(Clone, Debug)]
+pub struct FeatureUnit {
+    pub id: u8,
+    pub source_id: u8,
+}
+
+/// A UAC2 clock selector or clock multiplier, which passes on the clock of
+/// another clock entity.
+#[derive(Clone, Debug)]
+pub struct ClockUnit {
+    pub id: u8,
+    /// the clock entities it is fed by, in the order of the selector pins
+    pub sources: Vec<u8>,
+}
+
+/// An alternate setting of an AudioStreaming interface.
+#[derive(Clone, Debug, Default)]
+pub struct StreamingInterface {
+    pub interface: u8,
+    pub alternate_setting: u8,
+    pub uac2: bool,
+    pub endpoint: Option<u8>,
+    pub terminal_link: Option<u8>,
+    pub format_tag: Option<u16>,
+    pub channels: u8,
+    /// UAC2 describes the channels in the AS_GENERAL descriptor
+    pub channel_config: Option<u32>,
+    pub subframe_size: u8,
+    pub bit_resolution: u8,
+    /// UAC2 reports the sample rates through requests to the clock source
+    pub sample_rates: Option<SampleRates>,
+}
+
+impl StreamingInterface {
+    pub fn is_output(&self) -> bool {
+        self.endpoint.is_some_and(|e| e & 0x80 == 0)
+    }
+
+    pub fn is_input(&self) -> bool {
+        self.endpoint.is_some_and(|e| e & 0x80 != 0)
+    }
+
+    pub fn endpoint_number(&self) -> Option<u8> {
+        self.endpoint.map(|e| e & 0x0f)
+    }
+
+    #[cfg(feature = "pipewire")]
+    pub fn audio_format(&self) -> Option<spa::param::audio::AudioFormat> {
+        // Samples are always left-justified within their subframe, so the
+        // bit resolution doesn't matter for the wire format.
+        Some(match (self.format_tag?, self.subframe_size) {
+            (FORMAT_TAG_PCM, 1) => spa::param::audio::AudioFormat::S8,
+            (FORMAT_TAG_PCM, 2) => spa::param::audio::AudioFormat::S16LE,
+            (FORMAT_TAG_PCM, 3) => spa::param::audio::AudioFormat::S24LE,
+            (FORMAT_TAG_PCM, 4) => spa::param::audio::AudioFormat::S32LE,
+            (FORMAT_TAG_PCM8, 1) => spa::param::audio::AudioFormat::U8,
+            (FORMAT_TAG_IEEE_FLOAT, 4) => spa::param::audio::AudioFormat::F32LE,
+            _ => return None,
+        })
+    }
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct Configuration {
+    pub input_terminals: Vec<InputTerminal>,
+    pub output_terminals: Vec<OutputTerminal>,
+    pub feature_units: Vec<FeatureUnit>,
+    pub clock_units: Vec<ClockUnit>,
+    pub streaming_interfaces: Vec<StreamingInterface>,
+}
+
+impl Configuration {
+    pub fn parse(data: &[u8]) -> anyhow::Result<Self> {
+        let mut config = Self::default();
+        let mut current_class = None;
+        let mut streaming: Option<StreamingInterface> = None;
+
+        let mut data = data;
+        while !data.is_empty() {
+            let length: usize = data[0].into();
+            if length < 2 || length > data.len() {
+                anyhow::bail!("invalid descriptor length: {length}");
+            }
+
+            let (descriptor, rest) = data.split_at(length);
+            data = rest;
+
+            match descriptor[1] {
+                DESCRIPTOR_TYPE_INTERFACE if length >= 9 => {
+                    config.streaming_interfaces.extend(streaming.take());
+
+                    current_class = Some((descriptor[5], descriptor[6], descriptor[7]));
+                    if descriptor[5] == CLASS_AUDIO && descriptor[6] == SUBCLASS_AUDIOSTREAMING {
+                        streaming = Some(StreamingInterface {
+                            interface: descriptor[2],
+                            alternate_setting: descriptor[3],
+                            uac2: descriptor[7] == PROTOCOL_UAC2,
+                            ..Default::default()
+                        });
+                    }
+                }
+                DESCRIPTOR_TYPE_ENDPOINT if length >= 7 => {
+                    if let Some(streaming) = &mut streaming
+                        && streaming.endpoint.is_none()
+                        && descriptor[3] & 0x03 == TRANSFER_TYPE_ISOCHRONOUS
+                    {
+                        streaming.endpoint = Some(descriptor[2]);
+                    }
+                }
+                DESCRIPTOR_TYPE_CS_INTERFACE if length >= 3 => match current_class {
+                    Some((CLASS_AUDIO, SUBCLASS_AUDIOCONTROL, protocol)) => {
+                        config.parse_control_descriptor(descriptor, protocol == PROTOCOL_UAC2);
+                    }
+                    Some((CLASS_AUDIO, SUBCLASS_AUDIOSTREAMING, _)) => {
+                        if let Some(streaming) = &mut streaming {
+                            parse_streaming_descriptor(streaming, descriptor);
+                        }
+                    }
+                    _ => (),
+                },
+                _ => (),
+            }
+        }
+
+        config.streaming_interfaces.extend(streaming.take());
+        config
+            .streaming_interfaces
+            .retain(|s| s.alternate_setting != 0 && s.endpoint.is_some());
+
+        Ok(config)
+    }
+
+    fn parse_control_descriptor(&mut self, descriptor: &[u8], uac2: bool) {
+        match descriptor[2] {
+            AC_INPUT_TERMINAL => {
+                self.input_terminals
+                    .extend(parse_input_terminal(descriptor, uac2));
+            }
+            AC_OUTPUT_TERMINAL if uac2 && descriptor.len() >= 12 => {
+                self.output_terminals.push(OutputTerminal {
+                    id: descriptor[3],
+                    source_id: descriptor[7],
+                    clock_source: Some(descriptor[8]),
+                });
+            }
+            AC_OUTPUT_TERMINAL if !uac2 && descriptor.len() >= 9 => {
+                self.output_terminals.push(OutputTerminal {
+                    id: descriptor[3],
+                    source_id: descriptor[7],
+                    clock_source: None,
+                });
+            }
+            AC_FEATURE_UNIT if descriptor.len() >= 5 => {
+                self.feature_units.push(FeatureUnit {
+                    id: descriptor[3],
+                    source_id: descriptor[4],
+                });
+            }
+            AC_CLOCK_SELECTOR if uac2 && descriptor.len() >= 5 => {
+                let pins = 5 + usize::from(descriptor[4]);
+                if let Some(sources) = descriptor.get(5..pins) {
+                    self.clock_units.push(ClockUnit {
+                        id: descriptor[3],
+                        sources: sources.to_vec(),
+                    });
+                }
+            }
+            AC_CLOCK_MULTIPLIER if uac2 && descriptor.len() >= 5 => {
+                self.clock_units.push(ClockUnit {
+                    id: descriptor[3],
+                    sources: vec![descriptor[4]],
+                });
+            }
+            _ => (),
+        }
+    }
+
+    pub fn streaming_interface(
+        &self,
+        interface: u8,
+        alternate_setting: u8,
+    ) -> Option<&StreamingInterface> {
+        self.streaming_interfaces
+            .iter()
+            .find(|s| s.interface == interface && s.alternate_setting == alternate_setting)
+    }
+
+    pub fn input_terminal(&self, id: u8) -> Option<&InputTerminal> {
+        self.input_terminals.iter().find(|t| t.id == id)
+    }
+
+    pub fn output_terminal(&self, id: u8) -> Option<&OutputTerminal> {
+        self.output_terminals.iter().find(|t| t.id == id)
+    }
+
+    /// returns the input terminal the audio of `streaming` originates from.
+    ///
+    /// For an output endpoint that's the USB streaming terminal itself, for an
+    /// input endpoint the chain is followed back from the output terminal
+    /// through the feature units, e.g. to the microphone.
+    pub fn source_terminal(&self, streaming: &StreamingInterface) -> Option<&InputTerminal> {
+        let link = streaming.terminal_link?;
+        if let Some(terminal) = self.input_terminal(link) {
+            return Some(terminal);
+        }
+
+        let mut id = self.output_terminal(link)?.source_id;
+        // bounded, in case the descriptors contain a loop
+        for _ in 0..8 {
+            if let Some(terminal) = self.input_terminal(id) {
+                return Some(terminal);
+            }
+            id = self.feature_units.iter().find(|u| u.id == id)?.source_id;
+        }
+
+        None
+    }
+
+    /// returns the feature units the audio of `streaming` passes.
+    pub fn feature_units(&self, streaming: &StreamingInterface) -> Vec<u8> {
+        let Some(link) = streaming.terminal_link else {
+            return Vec::new();
+        };
+
+        match self.output_terminal(link) {
+            // recorded audio, from the source terminal to the USB streaming one
+            Some(terminal) => self.feature_unit_chain(terminal.source_id).0,
+            // played audio, from the USB streaming terminal to e.g. the speaker
+            None => self
+                .feature_units
+                .iter()
+                .filter(|u| self.feature_unit_chain(u.id).1 == link)
+                .map(|u| u.id)
+                .collect(),
+        }
+    }
+
+    /// returns the feature units from `id` back to the entity they're fed
+    /// by, and that entity.
+    fn feature_unit_chain(&self, mut id: u8) -> (Vec<u8>, u8) {
+        let mut units = Vec::new();
+        // bounded, in case the descriptors contain a loop
+        for _ in 0..8 {
+            let Some(unit) = self.feature_units.iter().find(|u| u.id == id) else {
+                break;
+            };
+            units.push(unit.id);
+            id = unit.source_id;
+        }
+
+        (units, id)
+    }
+
+    /// returns the UAC2 clock source that drives `streaming`.
+    ///
+    /// The terminal might be clocked through selectors and multipliers, the
+    /// sampling frequency is only set at the clock source behind them.
+    pub fn clock_source(&self, streaming: &StreamingInterface) -> Option<u8> {
+        let link = streaming.terminal_link?;
+        let mut id = match self.input_terminal(link) {
+            Some(terminal) => terminal.clock_source,
+            None => self.output_terminal(link)?.clock_source,
+        }?;
+
+        // bounded, in case the descriptors contain a loop
+        for _ in 0..8 {
+            let Some(unit) = self.clock_units.iter().find(|u| u.id == id) else {
+                return Some(id);
+            };
+            // the host might switch a selector to another pin, which isn't
+            // followed
+            id = *unit.sources.first()?;
+        }
+
+        None
+    }
+
+    /// returns the channel positions of the audio sent to `streaming`.
+    #[cfg(feature = "pipewire")]
+    pub fn channel_positions(
+        &self,
+        streaming: &StreamingInterface,
+    ) -> Vec<spa::sys::spa_audio_channel> {
+        let channel_config = streaming
+            .channel_config
+            .filter(|c| *c != 0)
+            .or_else(|| self.source_terminal(streaming).map(|t| t.channel_config))
+            .unwrap_or(0);
+
+        channel_positions(streaming.channels, channel_config)
+    }
+}
+
+fn parse_input_terminal(descriptor: &[u8], uac2: bool) -> Option<InputTerminal> {
+    if uac2 {
+        let config = descriptor.get(9..13)?;
+        Some(InputTerminal {
+            id: descriptor[3],
+            channel_config: u32::from_le_bytes([config[0], config[1], config[2], config[3]]),
+            clock_source: Some(descriptor[7]),
+        })
+    } else {
+        let config = descriptor.get(8..10)?;
+        Some(InputTerminal {
+            id: descriptor[3],
+            channel_config: u16::from_le_bytes([config[0], config[1]]).into(),
+            clock_source: None,
+        })
+    }
+}
+
+fn parse_streaming_descriptor(streaming: &mut StreamingInterface, descriptor: &[u8]) {
+    match descriptor[2] {
+        AS_GENERAL if streaming.uac2 && descriptor.len() >= 15 => {
+            streaming.terminal_link = Some(descriptor[3]);
+
+            // bmFormats is a bitmap, use the first format type I supports
+            let formats =
+                u32::from_le_bytes([descriptor[6], descriptor[7], descriptor[8], descriptor[9]]);
+            streaming.format_tag = match formats.trailing_zeros() {
+                0 => Some(FORMAT_TAG_PCM),
+                1 => Some(FORMAT_TAG_PCM8),
+                2 => Some(FORMAT_TAG_IEEE_FLOAT),
+                _ => None,
+            };
+
+            streaming.channels = descriptor[10];
+            streaming.channel_config = Some(u32::from_le_bytes([
+                descriptor[11],
+                descriptor[12],
+                descriptor[13],
+                descriptor[14],
+            ]));
+        }
+        AS_GENERAL if !streaming.uac2 && descriptor.len() >= 7 => {
+            streaming.terminal_link = Some(descriptor[3]);
+            streaming.format_tag = Some(u16::from_le_bytes([descriptor[5], descriptor[6]]));
+        }
+        AS_FORMAT_TYPE
+            if streaming.uac2 && descriptor.len() >= 6 && descriptor[3] == FORMAT_TYPE_I =>
+        {
+            streaming.subframe_size = descriptor[4];
+            streaming.bit_resolution = descriptor[5];
+        }
+        AS_FORMAT_TYPE
+            if !streaming.uac2 && descriptor.len() >= 8 && descriptor[3] == FORMAT_TYPE_I =>
+        {
+            streaming.channels = descriptor[4];
+            streaming.subframe_size = descriptor[5];
+            streaming.bit_resolution = descriptor[6];
+
+            let rates: Vec<u32> = descriptor[8..]
+                .chunks_exact(3)
+                .map(|f| u32::from_le_bytes([f[0], f[1], f[2], 0]))
+                .collect();
+            streaming.sample_rates = match descriptor[7] {
+                0 if rates.len() >= 2 => Some(SampleRates::Continuous(rates[0], rates[1])),
+                0 => None,
+                n => Some(SampleRates::Discrete(
+                    rates.into_iter().take(n.into()).collect(),
+                )),
+            };
+        }
+        _ => (),
+    }
+}
+
+/// converts a UAC1 `wChannelConfig` or UAC2 `bmChannelConfig` bitmap to
+/// pipewire channel positions.
+#[cfg(feature = "pipewire")]
+fn channel_positions(channels: u8, channel_config: u32) -> Vec<spa::sys::spa_audio_channel> {
+    // same mapping as the linux kernel uses, UAC2 only appends to the UAC1 bits
+    const POSITIONS: [spa::sys::spa_audio_channel; 27] = [
+        spa::sys::SPA_AUDIO_CHANNEL_FL,
+        spa::sys::SPA_AUDIO_CHANNEL_FR,
+        spa::sys::SPA_AUDIO_CHANNEL_FC,
+        spa::sys::SPA_AUDIO_CHANNEL_LFE,
+        spa::sys::SPA_AUDIO_CHANNEL_RL,
+        spa::sys::SPA_AUDIO_CHANNEL_RR,
+        spa::sys::SPA_AUDIO_CHANNEL_FLC,
+        spa::sys::SPA_AUDIO_CHANNEL_FRC,
+        spa::sys::SPA_AUDIO_CHANNEL_RC,
+        spa::sys::SPA_AUDIO_CHANNEL_SL,
+        spa::sys::SPA_AUDIO_CHANNEL_SR,
+        spa::sys::SPA_AUDIO_CHANNEL_TC,
+        spa::sys::SPA_AUDIO_CHANNEL_TFL,
+        spa::sys::SPA_AUDIO_CHANNEL_TFC,
+        spa::sys::SPA_AUDIO_CHANNEL_TFR,
+        spa::sys::SPA_AUDIO_CHANNEL_TRL,
+        spa::sys::SPA_AUDIO_CHANNEL_TRC,
+        spa::sys::SPA_AUDIO_CHANNEL_TRR,
+        spa::sys::SPA_AUDIO_CHANNEL_TFLC,
+        spa::sys::SPA_AUDIO_CHANNEL_TFRC,
+        spa::sys::SPA_AUDIO_CHANNEL_LLFE,
+        spa::sys::SPA_AUDIO_CHANNEL_RLFE,
+        spa::sys::SPA_AUDIO_CHANNEL_TSL,
+        spa::sys::SPA_AUDIO_CHANNEL_TSR,
+        spa::sys::SPA_AUDIO_CHANNEL_BC,
+        spa::sys::SPA_AUDIO_CHANNEL_BLC,
+        spa::sys::SPA_AUDIO_CHANNEL_BRC,
+    ];
+
+    if channels == 1 && channel_config == 0 {
+        return vec![spa::sys::SPA_AUDIO_CHANNEL_MONO];
+    }
+
+    let mut positions: Vec<_> = POSITIONS
+        .iter()
+        .enumerate()
+        .filter(|(bit, _)| channel_config & (1 << bit) != 0)
+        .map(|(_, position)| *position)
+        .take(channels.into())
+        .collect();
+
+    // channels without a spatial location
+    let mut aux = spa::sys::SPA_AUDIO_CHANNEL_AUX0;
+    while positions.len() < channels.into() {
+        positions.push(aux);
+        aux += 1;
+    }
+
+    positions
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// a headset with a stereo speaker in 16 or 24 bit and a mono microphone
+    const UAC1: &[&[u8]] = &[
+        // configuration
+        &[0x09, 0x02, 0x08, 0x01, 0x04, 0x01, 0x00, 0x80, 0x32],
+        // AudioControl interface
+        &[0x09, 0x04, 0x00, 0x00, 0x00, 0x01, 0x01, 0x00, 0x00],
+        // header
+        &[0x0a, 0x24, 0x01, 0x00, 0x01, 0x47, 0x00, 0x02, 0x01, 0x02],
+        // input terminal 1, USB streaming
+        &[
+            0x0c, 0x24, 0x02, 0x01, 0x01, 0x01, 0x00, 0x02, 0x03, 0x00, 0x00, 0x00,
+        ],
+        // feature unit 2
+        &[0x0a, 0x24, 0x06, 0x02, 0x01, 0x01, 0x01, 0x02, 0x02, 0x00],
+        // output terminal 3, speaker
+        &[0x09, 0x24, 0x03, 0x03, 0x01, 0x03, 0x00, 0x02, 0x00],
+        // input terminal 4, microphone
+        &[
+            0x0c, 0x24, 0x02, 0x04, 0x01, 0x02, 0x00, 0x01, 0x00, 0x00, 0x00, 0x00,
+        ],
+        // feature unit 5
+        &[0x09, 0x24, 0x06, 0x05, 0x04, 0x01, 0x03, 0x00, 0x00],
+        // output terminal 6, USB streaming
+        &[0x09, 0x24, 0x03, 0x06, 0x01, 0x01, 0x00, 0x05, 0x00],
+        // interface 1, zero bandwidth
+        &[0x09, 0x04, 0x01, 0x00, 0x00, 0x01, 0x02, 0x00, 0x00],
+        // interface 1, 16 bit
+        &[0x09, 0x04, 0x01, 0x01, 0x01, 0x01, 0x02, 0x00, 0x00],
+        // AS_GENERAL
+        &[0x07, 0x24, 0x01, 0x01, 0x01, 0x01, 0x00],
+        // FORMAT_TYPE, 44.1 and 48 kHz
+        &[
+            0x0e, 0x24, 0x02, 0x01, 0x02, 0x02, 0x10, 0x02, 0x44, 0xac, 0x00, 0x80, 0xbb, 0x00,
+        ],
+        // endpoint 0x01
+        &[0x09, 0x05, 0x01, 0x09, 0xc8, 0x00, 0x01, 0x00, 0x00],
+        &[0x07, 0x25, 0x01, 0x01, 0x00, 0x00, 0x00],
+        // interface 1, 24 bit
+        &[0x09, 0x04, 0x01, 0x02, 0x01, 0x01, 0x02, 0x00, 0x00],
+        // AS_GENERAL
+        &[0x07, 0x24, 0x01, 0x01, 0x01, 0x01, 0x00],
+        // FORMAT_TYPE, 48 kHz
+        &[
+            0x0b, 0x24, 0x02, 0x01, 0x02, 0x03, 0x18, 0x01, 0x80, 0xbb, 0x00,
+        ],
+        // endpoint 0x01
+        &[0x09, 0x05, 0x01, 0x09, 0x20, 0x01, 0x01, 0x00, 0x00],
+        &[0x07, 0x25, 0x01, 0x01, 0x00, 0x00, 0x00],
+        // interface 2, zero bandwidth
+        &[0x09, 0x04, 0x02, 0x00, 0x00, 0x01, 0x02, 0x00, 0x00],
+        // interface 2, 16 bit
+        &[0x09, 0x04, 0x02, 0x01, 0x01, 0x01, 0x02, 0x00, 0x00],
+        // AS_GENERAL
+        &[0x07, 0x24, 0x01, 0x06, 0x01, 0x01, 0x00],
+        // FORMAT_TYPE, 48 kHz
+        &[
+            0x0b, 0x24, 0x02, 0x01, 0x01, 0x02, 0x10, 0x01, 0x80, 0xbb, 0x00,
+        ],
+        // endpoint 0x82
+        &[0x09, 0x05, 0x82, 0x05, 0x64, 0x00, 0x01, 0x00, 0x00],
+        &[0x07, 0x25, 0x01, 0x00, 0x00, 0x00, 0x00],
+        // HID interface
+        &[0x09, 0x04, 0x03, 0x00, 0x01, 0x03, 0x00, 0x00, 0x00],
+        &[0x09, 0x21, 0x11, 0x01, 0x00, 0x01, 0x22, 0x3c, 0x00],
+        // endpoint 0x83
+        &[0x07, 0x05, 0x83, 0x03, 0x04, 0x00, 0x01],
+    ];
+
+    /// a UAC2 speaker, clocked through a multiplier and a selector
+    const UAC2: &[&[u8]] = &[
+        // configuration
+        &[0x09, 0x02, 0xe5, 0x00, 0x02, 0x01, 0x00, 0x80, 0xfa],
+        // interface association
+        &[0x08, 0x0b, 0x00, 0x02, 0x01, 0x00, 0x20, 0x00],
+        // AudioControl interface
+        &[0x09, 0x04, 0x00, 0x00, 0x00, 0x01, 0x01, 0x20, 0x00],
+        // header
+        &[0x09, 0x24, 0x01, 0x00, 0x02, 0x08, 0x58, 0x00, 0x00],
+        // clock source 0x10
+        &[0x08, 0x24, 0x0a, 0x10, 0x03, 0x07, 0x00, 0x00],
+        // clock source 0x11
+        &[0x08, 0x24, 0x0a, 0x11, 0x01, 0x01, 0x00, 0x00],
+        // clock selector 0x12
+        &[0x09, 0x24, 0x0b, 0x12, 0x02, 0x10, 0x11, 0x03, 0x00],
+        // clock multiplier 0x13
+        &[0x07, 0x24, 0x0c, 0x13, 0x12, 0x00, 0x00],
+        // input terminal 1, USB streaming
+        &[
+            0x11, 0x24, 0x02, 0x01, 0x01, 0x01, 0x00, 0x13, 0x02, 0x03, 0x00, 0x00, 0x00, 0x00,
+            0x00, 0x00, 0x00,
+        ],
+        // feature unit 2
+        &[
+            0x12, 0x24, 0x06, 0x02, 0x01, 0x0f, 0x00, 0x00, 0x00, 0x0c, 0x00, 0x00, 0x00, 0x0c,
+            0x00, 0x00, 0x00, 0x00,
+        ],
+        // output terminal 3, speaker
+        &[
+            0x0c, 0x24, 0x03, 0x03, 0x02, 0x03, 0x00, 0x02, 0x13, 0x00, 0x00, 0x00,
+        ],
+        // interface 1, zero bandwidth
+        &[0x09, 0x04, 0x01, 0x00, 0x00, 0x01, 0x02, 0x20, 0x00],
+        // interface 1, 24 bit
+        &[0x09, 0x04, 0x01, 0x01, 0x02, 0x01, 0x02, 0x20, 0x00],
+        // AS_GENERAL
+        &[
+            0x10, 0x24, 0x01, 0x01, 0x00, 0x01, 0x01, 0x00, 0x00, 0x00, 0x02, 0x03, 0x00, 0x00,
+            0x00, 0x00,
+        ],
+        // FORMAT_TYPE
+        &[0x06, 0x24, 0x02, 0x01, 0x04, 0x18],
+        // endpoint 0x01
+        &[0x07, 0x05, 0x01, 0x05, 0x00, 0x04, 0x01],
+        &[0x08, 0x25, 0x01, 0x00, 0x00, 0x00, 0x00, 0x00],
+        // feedback endpoint 0x81
+        &[0x07, 0x05, 0x81, 0x11, 0x04, 0x00, 0x04],
+        // interface 1, 16 bit
+        &[0x09, 0x04, 0x01, 0x02, 0x02, 0x01, 0x02, 0x20, 0x00],
+        // AS_GENERAL
+        &[
+            0x10, 0x24, 0x01, 0x01, 0x00, 0x01, 0x01, 0x00, 0x00, 0x00, 0x02, 0x03, 0x00, 0x00,
+            0x00, 0x00,
+        ],
+        // FORMAT_TYPE
+        &[0x06, 0x24, 0x02, 0x01, 0x02, 0x10],
+        // endpoint 0x01
+        &[0x07, 0x05, 0x01, 0x05, 0x00, 0x02, 0x01],
+        &[0x08, 0x25, 0x01, 0x00, 0x00, 0x00, 0x00, 0x00],
+        // feedback endpoint 0x81
+        &[0x07, 0x05, 0x81, 0x11, 0x04, 0x00, 0x04],
+    ];
+
+    fn parse(dump: &[&[u8]]) -> Configuration {
+        Configuration::parse(&dump.concat()).unwrap()
+    }
+
+    #[test]
+    fn uac1() {
+        let config = parse(UAC1);
+
+        let alternate_settings: Vec<_> = config
+            .streaming_interfaces
+            .iter()
+            .map(|s| (s.interface, s.alternate_setting))
+            .collect();
+        assert_eq!(alternate_settings, [(1, 1), (1, 2), (2, 1)]);
+
+        let speaker = config.streaming_interface(1, 1).unwrap();
+        assert!(!speaker.uac2);
+        assert!(speaker.is_output());
+        assert_eq!(speaker.endpoint_number(), Some(1));
+        assert_eq!(speaker.format_tag, Some(FORMAT_TAG_PCM));
+        assert_eq!(speaker.channels, 2);
+        assert_eq!(speaker.subframe_size, 2);
+        assert_eq!(speaker.bit_resolution, 16);
+        assert!(
+            matches!(&speaker.sample_rates, Some(SampleRates::Discrete(r)) if r == &[44100, 48000])
+        );
+        assert_eq!(config.source_terminal(speaker).unwrap().id, 1);
+        assert_eq!(config.source_terminal(speaker).unwrap().channel_config, 3);
+        assert_eq!(config.feature_units(speaker), [2]);
+        assert_eq!(config.clock_source(speaker), None);
+
+        let speaker = config.streaming_interface(1, 2).unwrap();
+        assert_eq!(speaker.subframe_size, 3);
+        assert_eq!(speaker.bit_resolution, 24);
+        assert!(matches!(&speaker.sample_rates, Some(SampleRates::Discrete(r)) if r == &[48000]));
+
+        let microphone = config.streaming_interface(2, 1).unwrap();
+        assert!(microphone.is_input());
+        assert_eq!(microphone.endpoint_number(), Some(2));
+        assert_eq!(microphone.channels, 1);
+        assert_eq!(config.source_terminal(microphone).unwrap().id, 4);
+        assert_eq!(config.feature_units(microphone), [5]);
+    }
+
+    #[test]
+    fn uac2() {
+        let config = parse(UAC2);
+
+        assert_eq!(config.streaming_interfaces.len(), 2);
+
+        let speaker = config.streaming_interface(1, 1).unwrap();
+        assert!(speaker.uac2);
+        // not the feedback endpoint
+        assert_eq!(speaker.endpoint, Some(0x01));
+        assert_eq!(speaker.format_tag, Some(FORMAT_TAG_PCM));
+        assert_eq!(speaker.channels, 2);
+        assert_eq!(speaker.channel_config, Some(3));
+        assert_eq!(speaker.subframe_size, 4);
+        assert_eq!(speaker.bit_resolution, 24);
+        assert!(speaker.sample_rates.is_none());
+        assert_eq!(config.feature_units(speaker), [2]);
+
+        let speaker = config.streaming_interface(1, 2).unwrap();
+        assert_eq!(speaker.subframe_size, 2);
+        assert_eq!(speaker.bit_resolution, 16);
+    }
+
+    #[test]
+    fn uac2_clock_chain() {
+        let config = parse(UAC2);
+        let speaker = config.streaming_interface(1, 1).unwrap();
+
+        assert_eq!(config.input_terminal(1).unwrap().clock_source, Some(0x13));
+        // through the multiplier and the first pin of the selector
+        assert_eq!(config.clock_source(speaker), Some(0x10));
+    }
+
+    #[test]
+    fn feature_unit_chains() {
+        let mut dump = UAC1.to_vec();
+        // feature unit 7 behind unit 2 on the way to the speaker
+        dump.insert(
+            5,
+            &[0x0a, 0x24, 0x06, 0x07, 0x02, 0x01, 0x01, 0x02, 0x02, 0x00],
+        );
+        // feature unit 8 in front of unit 5 on the way from the microphone
+        dump[8] = &[0x09, 0x24, 0x06, 0x05, 0x08, 0x01, 0x03, 0x00, 0x00];
+        dump.insert(8, &[0x09, 0x24, 0x06, 0x08, 0x04, 0x01, 0x03, 0x00, 0x00]);
+        let config = parse(&dump);
+
+        let speaker = config.streaming_interface(1, 1).unwrap();
+        assert_eq!(config.feature_units(speaker), [2, 7]);
+        assert_eq!(config.source_terminal(speaker).unwrap().id, 1);
+
+        let microphone = config.streaming_interface(2, 1).unwrap();
+        assert_eq!(config.feature_units(microphone), [5, 8]);
+        assert_eq!(config.source_terminal(microphone).unwrap().id, 4);
+    }
+
+    #[test]
+    fn clock_loop() {
+        let mut config = parse(UAC2);
+        config.clock_units.push(ClockUnit {
+            id: 0x10,
+            sources: vec![0x13],
+        });
+        let speaker = config.streaming_interface(1, 1).unwrap();
+
+        assert_eq!(config.clock_source(speaker), None);
+    }
+
+    #[test]
+    fn truncated() {
+        let data = UAC1.concat();
+        assert!(Configuration::parse(&data[..data.len() - 3]).is_err());
+
+        // a configuration descriptor read with wLength 9
+        let config = Configuration::parse(&data[..9]).unwrap();
+        assert!(config.streaming_interfaces.is_empty());
+
+        // descriptors too short for their type are skipped
+        let mut dump = UAC1.to_vec();
+        dump[3] = &[0x04, 0x24, 0x02, 0x01];
+        dump[13] = &[0x05, 0x05, 0x01, 0x09, 0xc8];
+        let config = parse(&dump);
+        assert!(config.input_terminal(1).is_none());
+        assert!(config.streaming_interface(1, 1).is_none());
+        assert!(config.streaming_interface(1, 2).is_some());
+    }
+
+    #[test]
+    fn invalid_length() {
+        for length in [0, 1] {
+            let mut data = UAC1.concat();
+            data[9] = length;
+            assert!(Configuration::parse(&data).is_err());
+        }
+    }
+
+    #[test]
+    fn range() {
+        let mut data = vec![0x02, 0x00];
+        for (min, max) in [(44100u32, 44100u32), (48000, 48000)] {
+            data.extend(min.to_le_bytes());
+            data.extend(max.to_le_bytes());
+            data.extend(0u32.to_le_bytes());
+        }
+        assert!(
+            matches!(SampleRates::parse_range(&data), Some(SampleRates::Discrete(r)) if r == [44100, 48000])
+        );
+
+        // a response cut short by the wLength of the request
+        assert!(SampleRates::parse_range(&data[..8]).is_none());
+        assert!(SampleRates::parse_range(&data[..1]).is_none());
+    }
+}
@@ -0,0 +1,211 @@
+use crate::control;
+use crate::usb;
+use std::collections::HashMap;
+
+/// The IDs of a device, read from its device descriptor.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct DeviceId {
+    pub vendor_id: u16,
+    pub product_id: u16,
+}
+
+/// Follows the enumeration of the devices on the bus, to know which device is
+/// at which address.
+#[derive(Default)]
+pub struct DeviceTable {
+    ids: HashMap<u8, DeviceId>,
+}
+
+impl DeviceTable {
+    /// returns the IDs of the device at `address`, if its device descriptor
+    /// was read.
+    pub fn get(&self, address: u8) -> Option<DeviceId> {
+        self.ids.get(&address).copied()
+    }
+
+    /// forgets all devices, which are back at address 0 after a reset.
+    pub fn bus_reset(&mut self) {
+        self.ids.clear();
+    }
+
+    pub fn transfer_received(&mut self, transfer: &control::ControlTransfer) {
+        match transfer.request() {
+            control::Request::SetAddress(address) => {
+                // a device that was replugged behind a hub might reuse the
+                // address of another one
+                self.ids.remove(&address);
+
+                if let Some(id) = self.ids.remove(&transfer.address) {
+                    log::info!(
+                        "device {:04x}:{:04x} moved from address {} to {address}",
+                        id.vendor_id,
+                        id.product_id,
+                        transfer.address
+                    );
+                    self.ids.insert(address, id);
+                }
+            }
+            control::Request::GetDescriptor {
+                descriptor_type: usb::DESCRIPTOR_TYPE_DEVICE,
+                ..
+            } => {
+                // the host might only read the first 8 bytes, which don't
+                // contain the IDs yet
+                let Some(ids) = transfer.data.get(8..12) else {
+                    return;
+                };
+                let id = DeviceId {
+                    vendor_id: u16::from_le_bytes([ids[0], ids[1]]),
+                    product_id: u16::from_le_bytes([ids[2], ids[3]]),
+                };
+
+                if self.ids.insert(transfer.address, id) != Some(id) {
+                    log::info!(
+                        "device {:04x}:{:04x} is at address {}",
+                        id.vendor_id,
+                        id.product_id,
+                        transfer.address
+                    );
+                }
+            }
+            _ => (),
+        }
+    }
+}
+
+/// Selects the device to capture by its address or its IDs.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct DeviceFilter {
+    pub address: Option<u8>,
+    pub vendor_id: Option<u16>,
+    pub product_id: Option<u16>,
+}
+
+impl DeviceFilter {
+    /// returns whether the device at `address` is selected.
+    ///
+    /// If IDs were given, the device descriptor has to be read by the host
+    /// before anything of the device is captured.
+    pub fn matches(&self, address: u8, devices: &DeviceTable) -> bool {
+        if self.address.is_some_and(|a| a != address) {
+            return false;
+        }
+        if self.vendor_id.is_none() && self.product_id.is_none() {
+            return true;
+        }
+
+        devices.get(address).is_some_and(|id| {
+            self.vendor_id.is_none_or(|v| v == id.vendor_id)
+                && self.product_id.is_none_or(|p| p == id.product_id)
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// the device descriptor of a headset with the IDs 046d:0a44
+    const DEVICE_DESCRIPTOR: [u8; 18] = [
+        0x12, 0x01, 0x00, 0x02, 0x00, 0x00, 0x00, 0x40, 0x6d, 0x04, 0x44, 0x0a, 0x01, 0x00, 0x01,
+        0x02, 0x00, 0x01,
+    ];
+    const HEADSET: DeviceId = DeviceId {
+        vendor_id: 0x046d,
+        product_id: 0x0a44,
+    };
+
+    fn get_device_descriptor(address: u8, data: &[u8]) -> control::ControlTransfer {
+        control::ControlTransfer {
+            address,
+            setup: usb::SetupPacket([0x80, 0x06, 0x00, 0x01, 0x00, 0x00, data.len() as u8, 0x00]),
+            data: data.to_vec(),
+        }
+    }
+
+    fn set_address(address: u8, new_address: u8) -> control::ControlTransfer {
+        control::ControlTransfer {
+            address,
+            setup: usb::SetupPacket([0x00, 0x05, new_address, 0x00, 0x00, 0x00, 0x00, 0x00]),
+            data: Vec::new(),
+        }
+    }
+
+    #[test]
+    fn enumeration() {
+        let mut devices = DeviceTable::default();
+        // the first 8 bytes don't contain the IDs yet
+        devices.transfer_received(&get_device_descriptor(0, &DEVICE_DESCRIPTOR[..8]));
+        assert_eq!(devices.get(0), None);
+
+        devices.transfer_received(&get_device_descriptor(0, &DEVICE_DESCRIPTOR));
+        assert_eq!(devices.get(0), Some(HEADSET));
+
+        devices.transfer_received(&set_address(0, 7));
+        assert_eq!(devices.get(0), None);
+        assert_eq!(devices.get(7), Some(HEADSET));
+    }
+
+    #[test]
+    fn address_reused() {
+        let mut devices = DeviceTable::default();
+        devices.transfer_received(&get_device_descriptor(7, &DEVICE_DESCRIPTOR));
+
+        // another device gets the address of the unplugged headset
+        devices.transfer_received(&set_address(0, 7));
+        assert_eq!(devices.get(7), None);
+    }
+
+    #[test]
+    fn bus_reset() {
+        let mut devices = DeviceTable::default();
+        devices.transfer_received(&get_device_descriptor(7, &DEVICE_DESCRIPTOR));
+        devices.bus_reset();
+        assert_eq!(devices.get(7), None);
+    }
+
+    #[test]
+    fn filter() {
+        let mut devices = DeviceTable::default();
+        devices.transfer_received(&get_device_descriptor(7, &DEVICE_DESCRIPTOR));
+
+        let all = DeviceFilter::default();
+        assert!(all.matches(7, &devices));
+        assert!(all.matches(9, &devices));
+
+        let address = DeviceFilter {
+            address: Some(7),
+            ..Default::default()
+        };
+        assert!(address.matches(7, &devices));
+        assert!(!address.matches(9, &devices));
+
+        let vendor = DeviceFilter {
+            vendor_id: Some(0x046d),
+            ..Default::default()
+        };
+        assert!(vendor.matches(7, &devices));
+        // the IDs of the device at 9 are unknown
+        assert!(!vendor.matches(9, &devices));
+
+        let ids = DeviceFilter {
+            vendor_id: Some(0x046d),
+            product_id: Some(0x0a44),
+            ..Default::default()
+        };
+        assert!(ids.matches(7, &devices));
+
+        let other_product = DeviceFilter {
+            vendor_id: Some(0x046d),
+            product_id: Some(0x0a45),
+            ..Default::default()
+        };
+        assert!(!other_product.matches(7, &devices));
+
+        let ids_and_address = DeviceFilter {
+            address: Some(9),
+            ..ids
+        };
+        assert!(!ids_and_address.matches(7, &devices));
+    }
+}
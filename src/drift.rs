@@ -0,0 +1,264 @@
+use std::time::Instant;
+
+/// frame numbers of SOF packets are 11 bits wide
+const FRAME_NUMBER_MASK: u16 = 0x7ff;
+/// duration of a USB frame, in seconds. High Speed repeats the frame number
+/// in all 8 microframes, so it counts frames at all speeds.
+const FRAME_DURATION: f64 = 1e-3;
+/// a longer gap between two SOFs, in frames, means that the bus was
+/// suspended or disconnected
+const MAX_FRAME_GAP: u16 = 16;
+/// time the fit needs to average out the jitter of the receive times, in seconds
+const MIN_DURATION: f64 = 10.0;
+
+/// proportional gain of the rate controller, per second
+const KP: f64 = 0.1;
+/// integral gain of the rate controller, per second squared, for a
+/// critically damped loop
+const KI: f64 = KP * KP / 4.0;
+/// maximum deviation of the rate from the feed-forward estimate
+const MAX_CORRECTION: f64 = 0.01;
+
+/// Estimates how fast the clock of the USB host runs compared to the system
+/// clock.
+///
+/// The frame numbers of the SOF packets count the milliseconds of the host,
+/// which are measured with the precise timestamps of the sniffer. The clock
+/// of the sniffer in turn is compared to the time its records are received.
+/// That is noisy, but the noise averages out in a linear fit over the whole
+/// capture.
+#[derive(Default)]
+pub struct DriftEstimator {
+    /// sniffer timestamp and system time of the first SOF
+    start: Option<(u64, Instant)>,
+    last_frame_number: u16,
+    last_timestamp: u64,
+    frames: u64,
+    /// sums of the linear fit of the system time over the sniffer time, in
+    /// seconds since `start`
+    n: f64,
+    sum_x: f64,
+    sum_y: f64,
+    sum_xx: f64,
+    sum_xy: f64,
+}
+
+impl DriftEstimator {
+    fn restart(&mut self, timestamp: u64, frame_number: u16, now: Instant) {
+        *self = Self {
+            start: Some((timestamp, now)),
+            last_frame_number: frame_number,
+            last_timestamp: timestamp,
+            ..Self::default()
+        };
+    }
+
+    pub fn sof_received(&mut self, timestamp: u64, frame_number: u16, now: Instant) {
+        let Some((start_timestamp, start_time)) = self.start else {
+            self.restart(timestamp, frame_number, now);
+            return;
+        };
+
+        let delta = frame_number.wrapping_sub(self.last_frame_number) & FRAME_NUMBER_MASK;
+        if delta == 0 {
+            // another microframe of the same frame
+            return;
+        }
+        if delta > MAX_FRAME_GAP || timestamp < self.last_timestamp {
+            log::debug!("gap of {delta} USB frames, restart drift estimation");
+            self.restart(timestamp, frame_number, now);
+            return;
+        }
+
+        self.last_frame_number = frame_number;
+        self.last_timestamp = timestamp;
+        self.frames += u64::from(delta);
+
+        let x = (timestamp - start_timestamp) as f64 / 1e9;
+        let y = now.duration_since(start_time).as_secs_f64();
+        self.n += 1.0;
+        self.sum_x += x;
+        self.sum_y += y;
+        self.sum_xx += x * x;
+        self.sum_xy += x * y;
+    }
+
+    /// returns the speed of the host clock relative to the system clock, once
+    /// enough SOFs have been received.
+    pub fn ratio(&self) -> Option<f64> {
+        let (start_timestamp, _) = self.start?;
+        let duration = (self.last_timestamp - start_timestamp) as f64 / 1e9;
+        if duration < MIN_DURATION {
+            return None;
+        }
+
+        // host frames per second of sniffer time
+        let host = self.frames as f64 * FRAME_DURATION / duration;
+        // seconds of system time per second of sniffer time
+        let system = (self.n * self.sum_xy - self.sum_x * self.sum_y)
+            / (self.n * self.sum_xx - self.sum_x * self.sum_x);
+        if !system.is_finite() || system <= 0.0 {
+            return None;
+        }
+
+        Some(host / system)
+    }
+}
+
+/// PI controller, which corrects the remaining drift by keeping the amount
+/// of buffered audio at a target level.
+#[derive(Default)]
+pub struct RateController {
+    integral: f64,
+}
+
+impl RateController {
+    /// returns the factor the consumption rate has to be multiplied with.
+    ///
+    /// `error` is the amount of audio buffered above the target, `elapsed` the
+    /// time since the last update, both in seconds.
+    pub fn update(&mut self, error: f64, elapsed: f64) -> f64 {
+        self.integral =
+            (self.integral + error * elapsed).clamp(-MAX_CORRECTION / KI, MAX_CORRECTION / KI);
+
+        let correction = KP * error + KI * self.integral;
+        1.0 + correction.clamp(-MAX_CORRECTION, MAX_CORRECTION)
+    }
+
+    /// forgets the accumulated error, e.g. after an underrun.
+    pub fn reset(&mut self) {
+        self.integral = 0.0;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// passes SOFs of a host, whose frames take `frame` seconds of sniffer
+    /// time, from `first` on for `seconds` seconds. The system clock runs
+    /// `system` times as fast as the sniffer clock.
+    fn sofs(
+        estimator: &mut DriftEstimator,
+        start: Instant,
+        first: u64,
+        seconds: u64,
+        frame: f64,
+        system: f64,
+    ) {
+        for index in first..first + seconds * 1000 {
+            let timestamp = (index as f64 * frame * 1e9) as u64;
+            let now = start + core::time::Duration::from_secs_f64(timestamp as f64 / 1e9 * system);
+            estimator.sof_received(timestamp, index as u16 & FRAME_NUMBER_MASK, now);
+        }
+    }
+
+    #[test]
+    fn known_ratio() {
+        let start = Instant::now();
+        let mut estimator = DriftEstimator::default();
+        // the host is 100 ppm fast
+        sofs(&mut estimator, start, 0, 5, 1e-3 / 1.0001, 1.0);
+        assert_eq!(estimator.ratio(), None);
+
+        sofs(&mut estimator, start, 5000, 10, 1e-3 / 1.0001, 1.0);
+        let ratio = estimator.ratio().unwrap();
+        assert!((ratio - 1.0001).abs() < 1e-7, "{ratio}");
+    }
+
+    #[test]
+    fn system_clock_drift() {
+        let start = Instant::now();
+        let mut estimator = DriftEstimator::default();
+        // the host runs exactly at the sniffer clock, the system clock is
+        // 50 ppm slow
+        sofs(&mut estimator, start, 0, 12, 1e-3, 0.99995);
+        let ratio = estimator.ratio().unwrap();
+        assert!((ratio - 1.0 / 0.99995).abs() < 1e-7, "{ratio}");
+    }
+
+    #[test]
+    fn microframes() {
+        let start = Instant::now();
+        let mut estimator = DriftEstimator::default();
+        for index in 0..12_000u64 * 8 {
+            let timestamp = index * 125_000;
+            let now = start + core::time::Duration::from_nanos(timestamp);
+            estimator.sof_received(timestamp, (index / 8) as u16 & FRAME_NUMBER_MASK, now);
+        }
+
+        let ratio = estimator.ratio().unwrap();
+        assert!((ratio - 1.0).abs() < 1e-7, "{ratio}");
+    }
+
+    #[test]
+    fn frame_gap_restarts() {
+        let start = Instant::now();
+        let mut estimator = DriftEstimator::default();
+        sofs(&mut estimator, start, 0, 12, 1e-3, 1.0);
+        assert!(estimator.ratio().is_some());
+
+        // the bus was suspended for 100 frames
+        sofs(&mut estimator, start, 12_100, 5, 1e-3, 1.0);
+        assert_eq!(estimator.ratio(), None);
+
+        // the frames before the gap don't count
+        sofs(&mut estimator, start, 17_100, 6, 1e-3, 1.0);
+        let ratio = estimator.ratio().unwrap();
+        assert!((ratio - 1.0).abs() < 1e-7, "{ratio}");
+    }
+
+    #[test]
+    fn timestamp_going_back_restarts() {
+        let start = Instant::now();
+        let mut estimator = DriftEstimator::default();
+        sofs(&mut estimator, start, 0, 12, 1e-3, 1.0);
+        assert!(estimator.ratio().is_some());
+
+        // a recording that starts over, with a consecutive frame number
+        let now = start + core::time::Duration::from_secs(13);
+        estimator.sof_received(0, 12_000 & FRAME_NUMBER_MASK, now);
+        assert_eq!(estimator.ratio(), None);
+    }
+
+    #[test]
+    fn controller_on_target() {
+        let mut controller = RateController::default();
+        assert_eq!(controller.update(0.0, 0.01), 1.0);
+    }
+
+    #[test]
+    fn controller_proportional() {
+        let mut controller = RateController::default();
+        // 10 ms above the target consumes the audio 0.1 % faster
+        let rate = controller.update(0.01, 0.0);
+        assert!((rate - 1.001).abs() < 1e-12, "{rate}");
+
+        let mut controller = RateController::default();
+        let rate = controller.update(-0.01, 0.0);
+        assert!((rate - 0.999).abs() < 1e-12, "{rate}");
+    }
+
+    #[test]
+    fn controller_clamps() {
+        let mut controller = RateController::default();
+        assert_eq!(controller.update(10.0, 0.01), 1.0 + MAX_CORRECTION);
+        assert_eq!(controller.update(-10.0, 0.01), 1.0 - MAX_CORRECTION);
+    }
+
+    #[test]
+    fn controller_integral() {
+        let mut controller = RateController::default();
+        // a constant error winds up the integral, but not beyond the limit
+        for _ in 0..100_000 {
+            controller.update(1.0, 0.01);
+        }
+        assert_eq!(controller.update(0.0, 0.01), 1.0 + MAX_CORRECTION);
+
+        // the integral takes as long to unwind
+        assert!(controller.update(-0.05, 0.01) > 1.0);
+
+        controller.reset();
+        assert_eq!(controller.update(0.0, 0.01), 1.0);
+    }
+}
@@ -0,0 +1,662 @@
+use crate::audio;
+use crate::flac;
+use anyhow::Context as _;
+use pipewire::spa;
+use std::io::{Seek as _, Write as _};
+
+/// number of bytes written between two updates of the header, so the file
+/// stays usable if the tool is killed
+const SYNC_INTERVAL: u64 = 1 << 20;
+/// the sizes in a WAV header are 32 bits wide
+const MAX_WAV_SIZE: u64 = u32::MAX as u64 - 1024;
+const WAV_HEADER_SIZE: usize = 68;
+const WAVE_FORMAT_EXTENSIBLE: u16 = 0xfffe;
+/// the part of the subformat GUIDs after the format tag
+const KSDATAFORMAT_SUBTYPE: [u8; 14] = [
+    0x00, 0x00, 0x00, 0x00, 0x10, 0x00, 0x80, 0x00, 0x00, 0xaa, 0x00, 0x38, 0x9b, 0x71,
+];
+const WAVE_FORMAT_PCM: u16 = 0x0001;
+const WAVE_FORMAT_IEEE_FLOAT: u16 = 0x0003;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Container {
+    Wav,
+    Flac,
+}
+
+/// Limits after which a new file is started.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Rotation {
+    pub duration: Option<core::time::Duration>,
+    /// in bytes
+    pub size: Option<u64>,
+}
+
+/// How the samples of a pipewire format are stored.
+#[derive(Clone, Copy, Debug)]
+struct Layout {
+    /// bytes per sample in the stream
+    size: usize,
+    /// number of significant bits, in the low bits of `size`
+    bits: u32,
+    big_endian: bool,
+    unsigned: bool,
+    float: bool,
+}
+
+impl Layout {
+    fn new(format: spa::param::audio::AudioFormat) -> anyhow::Result<Self> {
+        use spa::param::audio::AudioFormat as F;
+
+        let (size, bits, big_endian, unsigned, float) = match format {
+            F::S8 => (1, 8, false, false, false),
+            F::U8 => (1, 8, false, true, false),
+            F::S16LE => (2, 16, false, false, false),
+            F::S16BE => (2, 16, true, false, false),
+            F::U16LE => (2, 16, false, true, false),
+            F::U16BE => (2, 16, true, true, false),
+            F::S24_32LE => (4, 24, false, false, false),
+            F::S24_32BE => (4, 24, true, false, false),
+            F::U24_32LE => (4, 24, false, true, false),
+            F::U24_32BE => (4, 24, true, true, false),
+            F::S32LE => (4, 32, false, false, false),
+            F::S32BE => (4, 32, true, false, false),
+            F::U32LE => (4, 32, false, true, false),
+            F::U32BE => (4, 32, true, true, false),
+            F::S24LE => (3, 24, false, false, false),
+            F::S24BE => (3, 24, true, false, false),
+            F::U24LE => (3, 24, false, true, false),
+            F::U24BE => (3, 24, true, true, false),
+            F::F32LE => (4, 32, false, false, true),
+            F::F32BE => (4, 32, true, false, true),
+            F::F64LE => (8, 64, false, false, true),
+            F::F64BE => (8, 64, true, false, true),
+            _ => anyhow::bail!("audio format {format:?} isn't supported in files"),
+        };
+
+        Ok(Self {
+            size,
+            bits,
+            big_endian,
+            unsigned,
+            float,
+        })
+    }
+
+    /// returns the bytes of the sample at the start of `data`, little endian.
+    fn raw(&self, data: &[u8]) -> u64 {
+        let bytes = &data[..self.size];
+        let fold = |v: u64, b: &u8| (v << 8) | u64::from(*b);
+        if self.big_endian {
+            bytes.iter().fold(0, fold)
+        } else {
+            bytes.iter().rev().fold(0, fold)
+        }
+    }
+
+    /// returns the integer sample at the start of `data`.
+    fn sample(&self, data: &[u8]) -> i32 {
+        let raw = self.raw(data) & ((1 << self.bits) - 1);
+        let sign = 1 << (self.bits - 1);
+        if self.unsigned {
+            (raw as i64 - sign as i64) as i32
+        } else {
+            ((raw ^ sign) as i64 - sign as i64) as i32
+        }
+    }
+}
+
+/// returns the WAVE_FORMAT_EXTENSIBLE speaker bit of `channel`.
+fn speaker_position(channel: spa::sys::spa_audio_channel) -> Option<u32> {
+    Some(match channel {
+        spa::sys::SPA_AUDIO_CHANNEL_FL => 0x1,
+        spa::sys::SPA_AUDIO_CHANNEL_FR => 0x2,
+        spa::sys::SPA_AUDIO_CHANNEL_FC => 0x4,
+        spa::sys::SPA_AUDIO_CHANNEL_LFE => 0x8,
+        spa::sys::SPA_AUDIO_CHANNEL_RL => 0x10,
+        spa::sys::SPA_AUDIO_CHANNEL_RR => 0x20,
+        spa::sys::SPA_AUDIO_CHANNEL_FLC => 0x40,
+        spa::sys::SPA_AUDIO_CHANNEL_FRC => 0x80,
+        spa::sys::SPA_AUDIO_CHANNEL_RC => 0x100,
+        spa::sys::SPA_AUDIO_CHANNEL_SL => 0x200,
+        spa::sys::SPA_AUDIO_CHANNEL_SR => 0x400,
+        spa::sys::SPA_AUDIO_CHANNEL_TC => 0x800,
+        spa::sys::SPA_AUDIO_CHANNEL_TFL => 0x1000,
+        spa::sys::SPA_AUDIO_CHANNEL_TFC => 0x2000,
+        spa::sys::SPA_AUDIO_CHANNEL_TFR => 0x4000,
+        spa::sys::SPA_AUDIO_CHANNEL_TRL => 0x8000,
+        spa::sys::SPA_AUDIO_CHANNEL_TRC => 0x10000,
+        spa::sys::SPA_AUDIO_CHANNEL_TRR => 0x20000,
+        _ => return None,
+    })
+}
+
+/// returns the speaker mask of `channels`, or 0 if they can't be expressed
+/// by one, which has to list them in the order of the bits.
+fn channel_mask(channels: &[spa::sys::spa_audio_channel]) -> u32 {
+    let mut mask = 0;
+    for channel in channels {
+        match speaker_position(*channel) {
+            Some(bit) if bit > mask => mask |= bit,
+            _ => return 0,
+        }
+    }
+
+    mask
+}
+
+enum Encoding {
+    Wav,
+    Flac(flac::Encoder),
+}
+
+/// One of the files written by `AudioFileWriter`.
+struct AudioFile {
+    path: std::path::PathBuf,
+    file: std::io::BufWriter<std::fs::File>,
+    encoding: Encoding,
+    /// number of sample frames written
+    frames: u64,
+    /// number of bytes written after the header
+    size: u64,
+    /// value of `size` when the header was last updated
+    synced: u64,
+    buffer: Vec<u8>,
+}
+
+/// Writes the audio of a stream to WAV or FLAC files.
+///
+/// A new file is started whenever the format changes or a rotation limit is
+/// reached. The first one is written to the given path, the following ones
+/// get a number appended to its name.
+pub struct AudioFileWriter {
+    container: Container,
+    path: std::path::PathBuf,
+    rotation: Rotation,
+    format: audio::StreamFormat,
+    layout: Layout,
+    stride: usize,
+    file: Option<AudioFile>,
+    /// number of files started so far
+    index: u32,
+    samples: Vec<i32>,
+}
+
+impl AudioFileWriter {
+    pub fn new(
+        container: Container,
+        path: std::path::PathBuf,
+        rotation: Rotation,
+        format: audio::StreamFormat,
+    ) -> anyhow::Result<Self> {
+        let mut writer = Self {
+            container,
+            path,
+            rotation,
+            layout: Layout::new(format.format)?,
+            stride: 0,
+            format,
+            file: None,
+            index: 0,
+            samples: Vec::new(),
+        };
+        writer.check_format()?;
+        writer.stride = writer.layout.size * writer.format.channels.len();
+
+        Ok(writer)
+    }
+
+    fn check_format(&self) -> anyhow::Result<()> {
+        anyhow::ensure!(!self.format.channels.is_empty(), "no channels");
+
+        match self.container {
+            Container::Wav => (),
+            Container::Flac => {
+                anyhow::ensure!(!self.layout.float, "FLAC doesn't support float samples");
+                anyhow::ensure!(
+                    (1..=8).contains(&self.format.channels.len()),
+                    "FLAC supports at most 8 channels"
+                );
+            }
+        }
+
+        Ok(())
+    }
+
+    /// returns the path of the next file.
+    fn next_path(&self) -> std::path::PathBuf {
+        if self.index == 0 {
+            return self.path.clone();
+        }
+
+        let stem = self.path.file_stem().unwrap_or_default().to_string_lossy();
+        let mut name = format!("{stem}-{}", self.index);
+        if let Some(extension) = self.path.extension() {
+            name = format!("{name}.{}", extension.to_string_lossy());
+        }
+        self.path.with_file_name(name)
+    }
+
+    fn header(&self, file: &AudioFile) -> Vec<u8> {
+        match &file.encoding {
+            Encoding::Wav => self.wav_header(file.size),
+            Encoding::Flac(encoder) => encoder.header(),
+        }
+    }
+
+    fn wav_header(&self, data_size: u64) -> Vec<u8> {
+        let layout = &self.layout;
+        // 24 bit samples are stored in 3 bytes, even if they are padded to 4
+        let sample_size = if layout.float {
+            layout.size
+        } else {
+            layout.bits.div_ceil(8) as usize
+        };
+        let channels = self.format.channels.len();
+        let block_align = sample_size * channels;
+        let data_size = u32::try_from(data_size).unwrap_or(u32::MAX);
+
+        let mut header = Vec::with_capacity(WAV_HEADER_SIZE);
+        header.extend_from_slice(b"RIFF");
+        header.extend_from_slice(
+            &(WAV_HEADER_SIZE as u32 - 8)
+                .saturating_add(data_size)
+                .to_le_bytes(),
+        );
+        header.extend_from_slice(b"WAVE");
+
+        header.extend_from_slice(b"fmt ");
+        header.extend_from_slice(&40u32.to_le_bytes());
+        header.extend_from_slice(&WAVE_FORMAT_EXTENSIBLE.to_le_bytes());
+        header.extend_from_slice(&(channels as u16).to_le_bytes());
+        header.extend_from_slice(&self.format.rate.to_le_bytes());
+        header.extend_from_slice(&(self.format.rate * block_align as u32).to_le_bytes());
+        header.extend_from_slice(&(block_align as u16).to_le_bytes());
+        header.extend_from_slice(&(sample_size as u16 * 8).to_le_bytes());
+        header.extend_from_slice(&22u16.to_le_bytes());
+        header.extend_from_slice(&(layout.bits as u16).to_le_bytes());
+        header.extend_from_slice(&channel_mask(&self.format.channels).to_le_bytes());
+        let subformat = if layout.float {
+            WAVE_FORMAT_IEEE_FLOAT
+        } else {
+            WAVE_FORMAT_PCM
+        };
+        header.extend_from_slice(&subformat.to_le_bytes());
+        header.extend_from_slice(&KSDATAFORMAT_SUBTYPE);
+
+        header.extend_from_slice(b"data");
+        header.extend_from_slice(&data_size.to_le_bytes());
+
+        header
+    }
+
+    fn open(&mut self) -> anyhow::Result<()> {
+        let path = self.next_path();
+        let file = std::fs::File::create(&path)
+            .with_context(|| format!("failed to create {}", path.display()))?;
+        log::info!("writing audio to {}", path.display());
+
+        let encoding = match self.container {
+            Container::Wav => Encoding::Wav,
+            Container::Flac => Encoding::Flac(flac::Encoder::new(
+                self.format.rate,
+                self.format.channels.len(),
+                self.layout.bits,
+            )),
+        };
+        let mut file = AudioFile {
+            path,
+            file: std::io::BufWriter::new(file),
+            encoding,
+            frames: 0,
+            size: 0,
+            synced: 0,
+            buffer: Vec::new(),
+        };
+        let header = self.header(&file);
+        file.file.write_all(&header)?;
+
+        self.index += 1;
+        self.file = Some(file);
+        Ok(())
+    }
+
+    /// writes the final header and closes the current file.
+    fn close(&mut self) -> anyhow::Result<()> {
+        let Some(mut file) = self.file.take() else {
+            return Ok(());
+        };
+
+        if let Encoding::Flac(encoder) = &mut file.encoding {
+            encoder.finish(&mut file.buffer);
+            file.file.write_all(&file.buffer)?;
+            file.buffer.clear();
+        }
+        self.sync(&mut file)
+            .with_context(|| format!("failed to finish {}", file.path.display()))
+    }
+
+    /// updates the sizes in the header.
+    fn sync(&self, file: &mut AudioFile) -> anyhow::Result<()> {
+        let header = self.header(file);
+        file.file.seek(std::io::SeekFrom::Start(0))?;
+        file.file.write_all(&header)?;
+        file.file.seek(std::io::SeekFrom::End(0))?;
+        file.file.flush()?;
+        file.synced = file.size;
+        Ok(())
+    }
+
+    /// returns true, if the current file reached a limit.
+    fn is_full(&self, file: &AudioFile) -> bool {
+        let duration = file.frames as f64 / f64::from(self.format.rate);
+        self.rotation
+            .duration
+            .is_some_and(|v| duration >= v.as_secs_f64())
+            || self.rotation.size.is_some_and(|v| file.size >= v)
+            || (self.container == Container::Wav && file.size >= MAX_WAV_SIZE)
+    }
+
+    /// appends whole sample frames of `data` to the current file.
+    pub fn write(&mut self, data: &[u8]) -> anyhow::Result<()> {
+        if self.file.as_ref().is_some_and(|f| self.is_full(f)) {
+            self.close()?;
+        }
+        if self.file.is_none() {
+            self.open()?;
+        }
+
+        let layout = self.layout;
+        let data = &data[..data.len() - data.len() % self.stride];
+        let mut file = self.file.take().unwrap();
+        file.buffer.clear();
+
+        match &mut file.encoding {
+            Encoding::Wav if layout.float => {
+                for sample in data.chunks_exact(layout.size) {
+                    file.buffer
+                        .extend_from_slice(&layout.raw(sample).to_le_bytes()[..layout.size]);
+                }
+            }
+            Encoding::Wav if layout.bits == 8 => {
+                // 8 bit WAV samples are unsigned
+                for sample in data.chunks_exact(layout.size) {
+                    file.buffer.push((layout.sample(sample) + 0x80) as u8);
+                }
+            }
+            Encoding::Wav => {
+                let size = layout.bits as usize / 8;
+                for sample in data.chunks_exact(layout.size) {
+                    file.buffer
+                        .extend_from_slice(&layout.sample(sample).to_le_bytes()[..size]);
+                }
+            }
+            Encoding::Flac(encoder) => {
+                self.samples.clear();
+                self.samples.extend(
+                    data.chunks_exact(layout.size)
+                        .map(|sample| layout.sample(sample)),
+                );
+                encoder.write(&self.samples, &mut file.buffer);
+            }
+        }
+
+        let result = file.file.write_all(&file.buffer);
+        file.frames += (data.len() / self.stride) as u64;
+        file.size += file.buffer.len() as u64;
+
+        let result = match result {
+            Ok(()) if file.size - file.synced >= SYNC_INTERVAL => self.sync(&mut file),
+            v => v.map_err(Into::into),
+        };
+        self.file = Some(file);
+        result
+    }
+
+    /// starts a new file with `format`, if it differs from the current one.
+    pub fn set_format(&mut self, format: audio::StreamFormat) -> anyhow::Result<()> {
+        if format == self.format {
+            return Ok(());
+        }
+        self.close()?;
+
+        self.layout = Layout::new(format.format)?;
+        self.format = format;
+        self.check_format()?;
+        self.stride = self.layout.size * self.format.channels.len();
+        Ok(())
+    }
+}
+
+/// Writes the audio to files once the format is known, see `AudioFileWriter`.
+pub struct FileSink {
+    container: Container,
+    path: std::path::PathBuf,
+    rotation: Rotation,
+    writer: Option<AudioFileWriter>,
+}
+
+impl FileSink {
+    pub fn new(container: Container, path: std::path::PathBuf, rotation: Rotation) -> Self {
+        Self {
+            container,
+            path,
+            rotation,
+            writer: None,
+        }
+    }
+}
+
+impl audio::AudioSink for FileSink {
+    fn open(&mut self, format: audio::StreamFormat, _active: bool) -> anyhow::Result<()> {
+        self.writer = Some(AudioFileWriter::new(
+            self.container,
+            self.path.clone(),
+            self.rotation,
+            format,
+        )?);
+        Ok(())
+    }
+
+    fn write(&mut self, data: &[u8]) -> anyhow::Result<()> {
+        match &mut self.writer {
+            Some(writer) => writer.write(data),
+            None => Ok(()),
+        }
+    }
+
+    fn set_format(&mut self, format: audio::StreamFormat) -> anyhow::Result<()> {
+        match &mut self.writer {
+            Some(writer) => writer.set_format(format),
+            None => Ok(()),
+        }
+    }
+}
+
+impl Drop for AudioFileWriter {
+    fn drop(&mut self) {
+        if let Err(e) = self.close() {
+            log::error!("{e:#}");
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use spa::param::audio::AudioFormat as F;
+
+    const INTEGER_FORMATS: [F; 18] = [
+        F::S8,
+        F::U8,
+        F::S16LE,
+        F::S16BE,
+        F::U16LE,
+        F::U16BE,
+        F::S24_32LE,
+        F::S24_32BE,
+        F::U24_32LE,
+        F::U24_32BE,
+        F::S32LE,
+        F::S32BE,
+        F::U32LE,
+        F::U32BE,
+        F::S24LE,
+        F::S24BE,
+        F::U24LE,
+        F::U24BE,
+    ];
+
+    /// returns the bytes of `value` in the stream format.
+    fn raw_sample(layout: &Layout, value: i32) -> Vec<u8> {
+        let mut value = i64::from(value);
+        if layout.unsigned {
+            value += 1 << (layout.bits - 1);
+        }
+        let raw = value as u64 & ((1 << layout.bits) - 1);
+
+        let mut bytes = raw.to_le_bytes()[..layout.size].to_vec();
+        if layout.big_endian {
+            bytes.reverse();
+        }
+        bytes
+    }
+
+    /// writes `frames` frames in `format` to a FLAC file and compares the
+    /// decoded samples.
+    fn round_trip(format: F, channels: usize, frames: usize) {
+        let path = std::env::temp_dir().join(format!(
+            "usbaudio-sniffer-{}-{format:?}-{channels}-{frames}.flac",
+            std::process::id()
+        ));
+        let layout = Layout::new(format).unwrap();
+        let samples = flac::tests::signal(layout.bits, channels, frames);
+
+        let mut writer = AudioFileWriter::new(
+            Container::Flac,
+            path.clone(),
+            Rotation::default(),
+            audio::StreamFormat {
+                format,
+                rate: 48000,
+                channels: [
+                    spa::sys::SPA_AUDIO_CHANNEL_FL,
+                    spa::sys::SPA_AUDIO_CHANNEL_FR,
+                ][..channels]
+                    .to_vec(),
+            },
+        )
+        .unwrap();
+        // in packets of 1 ms
+        let data: Vec<u8> = samples
+            .iter()
+            .flat_map(|v| raw_sample(&layout, *v))
+            .collect();
+        for packet in data.chunks(48 * layout.size * channels) {
+            writer.write(packet).unwrap();
+        }
+        drop(writer);
+
+        let (params, decoded_frames, decoded) = flac::tests::decode(std::fs::read(&path).unwrap());
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(params.sample_rate, Some(48000));
+        assert_eq!(params.channels.map(|c| c.count()), Some(channels));
+        assert_eq!(params.bits_per_sample, Some(layout.bits));
+        assert_eq!(decoded_frames, Some(frames as u64));
+
+        let shift = 32 - layout.bits;
+        assert!(
+            decoded
+                .iter()
+                .map(|v| v >> shift)
+                .eq(samples.iter().copied()),
+            "{format:?} with {channels} channels"
+        );
+    }
+
+    #[test]
+    fn flac_mono() {
+        for format in INTEGER_FORMATS {
+            // two full blocks and a partial one
+            round_trip(format, 1, 2 * 4096 + 123);
+        }
+    }
+
+    #[test]
+    fn flac_stereo() {
+        for format in INTEGER_FORMATS {
+            round_trip(format, 2, 2 * 4096 + 123);
+        }
+    }
+
+    /// returns the data size in the header of a WAV file.
+    fn wav_data_size(path: &std::path::Path) -> u32 {
+        let data = std::fs::read(path).unwrap();
+        let size = u32::from_le_bytes(
+            data[WAV_HEADER_SIZE - 4..WAV_HEADER_SIZE]
+                .try_into()
+                .unwrap(),
+        );
+        assert_eq!(data.len(), WAV_HEADER_SIZE + size as usize);
+        size
+    }
+
+    #[test]
+    fn same_format_continues_file() {
+        let dir = std::env::temp_dir();
+        let name = format!("usbaudio-sniffer-{}-session", std::process::id());
+        let path = dir.join(format!("{name}.wav"));
+        let format = audio::StreamFormat {
+            format: F::S16LE,
+            rate: 48000,
+            channels: vec![
+                spa::sys::SPA_AUDIO_CHANNEL_FL,
+                spa::sys::SPA_AUDIO_CHANNEL_FR,
+            ],
+        };
+
+        let mut writer = AudioFileWriter::new(
+            Container::Wav,
+            path.clone(),
+            Rotation::default(),
+            format.clone(),
+        )
+        .unwrap();
+        writer.write(&[0; 192]).unwrap();
+        // the host pauses and resumes the playback
+        writer.set_format(format.clone()).unwrap();
+        writer.write(&[0; 192]).unwrap();
+
+        // only a different format starts a new file
+        writer
+            .set_format(audio::StreamFormat {
+                rate: 44100,
+                ..format
+            })
+            .unwrap();
+        writer.write(&[0; 176]).unwrap();
+        drop(writer);
+
+        let second = dir.join(format!("{name}-1.wav"));
+        assert_eq!(wav_data_size(&path), 384);
+        assert_eq!(wav_data_size(&second), 176);
+        assert!(!dir.join(format!("{name}-2.wav")).exists());
+        std::fs::remove_file(&path).unwrap();
+        std::fs::remove_file(&second).unwrap();
+    }
+
+    #[test]
+    fn flac_rejects_float() {
+        let result = AudioFileWriter::new(
+            Container::Flac,
+            std::env::temp_dir().join("unused.flac"),
+            Rotation::default(),
+            audio::StreamFormat {
+                format: F::F32LE,
+                rate: 48000,
+                channels: vec![spa::sys::SPA_AUDIO_CHANNEL_MONO],
+            },
+        );
+        assert!(result.is_err());
+    }
+}
@@ -0,0 +1,510 @@
+/// samples per channel in a frame
+const BLOCK_SIZE: usize = 4096;
+/// highest order of the fixed predictors
+const MAX_FIXED_ORDER: usize = 4;
+/// highest parameter of the 4 bit rice coding method
+const MAX_RICE_PARAMETER: u32 = 14;
+/// highest parameter of the 5 bit rice coding method
+const MAX_RICE2_PARAMETER: u32 = 30;
+
+const METADATA_STREAMINFO: u8 = 0;
+const STREAMINFO_LENGTH: u32 = 34;
+
+const SUBFRAME_CONSTANT: u64 = 0b000000;
+const SUBFRAME_VERBATIM: u64 = 0b000001;
+const SUBFRAME_FIXED: u64 = 0b001000;
+
+/// Writes the bits of a FLAC frame, most significant first.
+#[derive(Default)]
+struct BitWriter {
+    data: Vec<u8>,
+    acc: u64,
+    bits: u32,
+}
+
+impl BitWriter {
+    /// appends the lowest `bits` bits of `value`, at most 32.
+    fn write(&mut self, value: u64, bits: u32) {
+        if bits == 0 {
+            return;
+        }
+
+        self.acc = (self.acc << bits) | (value & ((1 << bits) - 1));
+        self.bits += bits;
+        while self.bits >= 8 {
+            self.bits -= 8;
+            self.data.push((self.acc >> self.bits) as u8);
+        }
+    }
+
+    fn write_signed(&mut self, value: i64, bits: u32) {
+        self.write(value as u64, bits);
+    }
+
+    /// appends `value` zeros followed by a one.
+    fn write_unary(&mut self, mut value: u64) {
+        while value >= 32 {
+            self.write(0, 32);
+            value -= 32;
+        }
+        self.write(1, value as u32 + 1);
+    }
+
+    /// pads the last byte with zeros.
+    fn align(&mut self) {
+        if self.bits > 0 {
+            self.write(0, 8 - self.bits);
+        }
+    }
+}
+
+fn crc8(data: &[u8]) -> u8 {
+    data.iter().fold(0, |crc, byte| {
+        (0..8).fold(crc ^ byte, |crc, _| {
+            if crc & 0x80 != 0 {
+                (crc << 1) ^ 0x07
+            } else {
+                crc << 1
+            }
+        })
+    })
+}
+
+fn crc16(data: &[u8]) -> u16 {
+    data.iter().fold(0, |crc, byte| {
+        (0..8).fold(crc ^ (u16::from(*byte) << 8), |crc, _| {
+            if crc & 0x8000 != 0 {
+                (crc << 1) ^ 0x8005
+            } else {
+                crc << 1
+            }
+        })
+    })
+}
+
+/// returns the code of the sample size in a frame header, or 0 to use the
+/// one from STREAMINFO.
+fn sample_size_code(bits: u32) -> u64 {
+    match bits {
+        8 => 0b001,
+        12 => 0b010,
+        16 => 0b100,
+        20 => 0b101,
+        24 => 0b110,
+        32 => 0b111,
+        _ => 0b000,
+    }
+}
+
+/// appends the frame number in the UTF-8 like coding of FLAC.
+fn write_coded_number(writer: &mut BitWriter, value: u64) {
+    if value < 0x80 {
+        writer.write(value, 8);
+        return;
+    }
+
+    // number of continuation bytes with 6 bits each
+    let continuation = (1..6).find(|n| value < 1 << (6 * n + 6 - n)).unwrap_or(6);
+    let prefix = (0xff00u64 >> (continuation + 1)) & 0xff;
+    writer.write(prefix | (value >> (6 * continuation)), 8);
+    for index in (0..continuation).rev() {
+        writer.write(0x80 | ((value >> (6 * index)) & 0x3f), 8);
+    }
+}
+
+/// returns the residual of the fixed predictor of `order` for `samples`.
+fn fixed_residual(samples: &[i32], order: usize, residual: &mut Vec<i64>) {
+    residual.clear();
+    residual.extend((order..samples.len()).map(|i| {
+        let x = |n: usize| i64::from(samples[i - n]);
+        match order {
+            0 => x(0),
+            1 => x(0) - x(1),
+            2 => x(0) - 2 * x(1) + x(2),
+            3 => x(0) - 3 * x(1) + 3 * x(2) - x(3),
+            _ => x(0) - 4 * x(1) + 6 * x(2) - 4 * x(3) + x(4),
+        }
+    }));
+}
+
+fn zigzag(value: i64) -> u64 {
+    ((value << 1) ^ (value >> 63)) as u64
+}
+
+/// returns the rice parameter for `residual` and the number of bits it
+/// takes with it.
+fn rice_parameter(residual: &[i64]) -> (u32, u64) {
+    let cost = |parameter: u32| {
+        residual
+            .iter()
+            .map(|v| (zigzag(*v) >> parameter) + 1 + u64::from(parameter))
+            .sum::<u64>()
+    };
+
+    let sum: u64 = residual.iter().map(|v| zigzag(*v)).sum();
+    let mean = sum / residual.len().max(1) as u64;
+    let estimate = mean.checked_ilog2().unwrap_or(0).min(MAX_RICE2_PARAMETER);
+
+    // the estimate is off by one at most
+    (estimate.saturating_sub(1)..=(estimate + 1).min(MAX_RICE2_PARAMETER))
+        .map(|parameter| (parameter, cost(parameter)))
+        .min_by_key(|(_, cost)| *cost)
+        .unwrap()
+}
+
+/// Encodes PCM samples as a FLAC stream.
+///
+/// Every channel is coded separately with the best of the fixed predictors,
+/// which is a lot simpler than the LPC of libFLAC, but still compresses well.
+pub struct Encoder {
+    rate: u32,
+    channels: usize,
+    bits: u32,
+    /// the samples of the current block, per channel
+    block: Vec<Vec<i32>>,
+    frame_number: u64,
+    /// number of samples per channel in all frames written so far
+    samples: u64,
+    residual: Vec<i64>,
+}
+
+impl Encoder {
+    pub fn new(rate: u32, channels: usize, bits: u32) -> Self {
+        Self {
+            rate,
+            channels,
+            bits,
+            block: vec![Vec::with_capacity(BLOCK_SIZE); channels],
+            frame_number: 0,
+            samples: 0,
+            residual: Vec::with_capacity(BLOCK_SIZE),
+        }
+    }
+
+    /// returns the signature and the STREAMINFO block, which has to be
+    /// written again once all frames are known.
+    pub fn header(&self) -> Vec<u8> {
+        let mut writer = BitWriter::default();
+        writer.data.extend_from_slice(b"fLaC");
+
+        // last metadata block
+        writer.write(1, 1);
+        writer.write(METADATA_STREAMINFO.into(), 7);
+        writer.write(STREAMINFO_LENGTH.into(), 24);
+
+        writer.write(BLOCK_SIZE as u64, 16);
+        writer.write(BLOCK_SIZE as u64, 16);
+        // the frame sizes are unknown
+        writer.write(0, 24);
+        writer.write(0, 24);
+        writer.write(self.rate.into(), 20);
+        writer.write(self.channels as u64 - 1, 3);
+        writer.write(u64::from(self.bits) - 1, 5);
+        writer.write(self.samples >> 32, 4);
+        writer.write(self.samples, 32);
+        // no MD5 signature
+        writer.data.extend_from_slice(&[0; 16]);
+
+        writer.data
+    }
+
+    /// encodes interleaved samples and appends the completed frames to `out`.
+    pub fn write(&mut self, samples: &[i32], out: &mut Vec<u8>) {
+        for frame in samples.chunks_exact(self.channels) {
+            for (channel, sample) in self.block.iter_mut().zip(frame) {
+                channel.push(*sample);
+            }
+
+            if self.block[0].len() == BLOCK_SIZE {
+                self.write_frame(out);
+            }
+        }
+    }
+
+    /// appends the incomplete last block to `out`.
+    pub fn finish(&mut self, out: &mut Vec<u8>) {
+        if !self.block[0].is_empty() {
+            self.write_frame(out);
+        }
+    }
+
+    fn write_frame(&mut self, out: &mut Vec<u8>) {
+        let len = self.block[0].len();
+        let mut writer = BitWriter::default();
+
+        writer.write(0b11_1111_1111_1110, 14);
+        writer.write(0, 1);
+        // fixed block size
+        writer.write(0, 1);
+        if len == BLOCK_SIZE {
+            writer.write(0b1100, 4);
+        } else {
+            // 16 bit block size at the end of the header
+            writer.write(0b0111, 4);
+        }
+        // rate from STREAMINFO
+        writer.write(0b0000, 4);
+        // independent channels
+        writer.write(self.channels as u64 - 1, 4);
+        // not all decoders take the sample size from STREAMINFO
+        writer.write(sample_size_code(self.bits), 3);
+        writer.write(0, 1);
+        write_coded_number(&mut writer, self.frame_number);
+        if len != BLOCK_SIZE {
+            writer.write(len as u64 - 1, 16);
+        }
+        let crc = crc8(&writer.data);
+        writer.write(crc.into(), 8);
+
+        let block = std::mem::take(&mut self.block);
+        for channel in &block {
+            self.write_subframe(&mut writer, channel);
+        }
+        self.block = block;
+
+        writer.align();
+        let crc = crc16(&writer.data);
+        writer.write(crc.into(), 16);
+        out.extend_from_slice(&writer.data);
+
+        for channel in &mut self.block {
+            channel.clear();
+        }
+        self.frame_number += 1;
+        self.samples += len as u64;
+    }
+
+    fn write_subframe(&mut self, writer: &mut BitWriter, samples: &[i32]) {
+        // zero padding bit, type and no wasted bits
+        let header = |writer: &mut BitWriter, kind: u64| {
+            writer.write(0, 1);
+            writer.write(kind, 6);
+            writer.write(0, 1);
+        };
+
+        if samples.iter().all(|v| *v == samples[0]) {
+            header(writer, SUBFRAME_CONSTANT);
+            writer.write_signed(samples[0].into(), self.bits);
+            return;
+        }
+
+        let verbatim = samples.len() as u64 * u64::from(self.bits);
+        let mut best = None;
+        for order in 0..=MAX_FIXED_ORDER.min(samples.len() - 1) {
+            fixed_residual(samples, order, &mut self.residual);
+            // the format limits the residual to 32 bits
+            if self.residual.iter().any(|v| i32::try_from(*v).is_err()) {
+                continue;
+            }
+
+            let (parameter, bits) = rice_parameter(&self.residual);
+            let bits = bits + order as u64 * u64::from(self.bits) + 2 + 4 + 5;
+            if bits < best.map_or(verbatim, |(_, _, v)| v) {
+                best = Some((order, parameter, bits));
+            }
+        }
+
+        let Some((order, parameter, _)) = best else {
+            header(writer, SUBFRAME_VERBATIM);
+            for sample in samples {
+                writer.write_signed((*sample).into(), self.bits);
+            }
+            return;
+        };
+
+        header(writer, SUBFRAME_FIXED | order as u64);
+        for sample in &samples[..order] {
+            writer.write_signed((*sample).into(), self.bits);
+        }
+
+        fixed_residual(samples, order, &mut self.residual);
+        if parameter <= MAX_RICE_PARAMETER {
+            writer.write(0b00, 2);
+            writer.write(0, 4);
+            writer.write(parameter.into(), 4);
+        } else {
+            writer.write(0b01, 2);
+            writer.write(0, 4);
+            writer.write(parameter.into(), 5);
+        }
+        for value in &self.residual {
+            let value = zigzag(*value);
+            writer.write_unary(value >> parameter);
+            writer.write(value, parameter);
+        }
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+
+    /// returns a tone with some full scale samples, and silence on the
+    /// second channel in the first block.
+    pub(crate) fn signal(bits: u32, channels: usize, frames: usize) -> Vec<i32> {
+        let max = (1i64 << (bits - 1)) - 1;
+        let min = -(1i64 << (bits - 1));
+
+        (0..frames)
+            .flat_map(|frame| {
+                (0..channels).map(move |channel| {
+                    if channel == 1 && frame < BLOCK_SIZE {
+                        return (max / 3) as i32;
+                    }
+                    match frame % 1000 {
+                        0 => max as i32,
+                        1 => min as i32,
+                        _ => {
+                            let phase = frame as f64 * (channel + 1) as f64 * 0.01;
+                            (phase.sin() * max as f64 * 0.9) as i32
+                        }
+                    }
+                })
+            })
+            .collect()
+    }
+
+    /// returns white noise over the full range of `bits`.
+    fn noise(bits: u32, samples: usize) -> Vec<i32> {
+        let mut state = 0x2545_f491_4f6c_dd1du64;
+        (0..samples)
+            .map(|_| {
+                state ^= state << 13;
+                state ^= state >> 7;
+                state ^= state << 17;
+                (state as i64 >> (64 - bits)) as i32
+            })
+            .collect()
+    }
+
+    /// returns the parameters, the number of frames and the samples of a
+    /// FLAC stream, scaled to 32 bits.
+    pub(crate) fn decode(
+        data: Vec<u8>,
+    ) -> (
+        symphonia_core::codecs::audio::AudioCodecParameters,
+        Option<u64>,
+        Vec<i32>,
+    ) {
+        use symphonia_core::codecs::audio::AudioDecoder as _;
+        use symphonia_core::formats::FormatReader as _;
+
+        let stream = symphonia_core::io::MediaSourceStream::new(
+            Box::new(std::io::Cursor::new(data)),
+            Default::default(),
+        );
+        let mut reader =
+            symphonia_bundle_flac::FlacReader::try_new(stream, Default::default()).unwrap();
+        let track = reader
+            .default_track(symphonia_core::formats::TrackType::Audio)
+            .unwrap();
+        let frames = track.num_frames;
+        let Some(symphonia_core::codecs::CodecParameters::Audio(params)) = &track.codec_params
+        else {
+            panic!("no audio track");
+        };
+        let params = params.clone();
+        let mut decoder =
+            symphonia_bundle_flac::FlacDecoder::try_new(&params, &Default::default()).unwrap();
+
+        let mut samples = Vec::new();
+        while let Some(packet) = reader.next_packet().unwrap() {
+            let mut interleaved = Vec::<i32>::new();
+            decoder
+                .decode(&packet)
+                .unwrap()
+                .copy_to_vec_interleaved(&mut interleaved);
+            samples.extend(interleaved);
+        }
+
+        (params, frames, samples)
+    }
+
+    /// encodes `samples` like a file writer in chunks of 1 ms and compares
+    /// the decoded samples.
+    fn round_trip(bits: u32, channels: usize, samples: &[i32]) {
+        let mut encoder = Encoder::new(48000, channels, bits);
+        let mut data = encoder.header();
+        for chunk in samples.chunks(48 * channels) {
+            encoder.write(chunk, &mut data);
+        }
+        encoder.finish(&mut data);
+        let header = encoder.header();
+        data[..header.len()].copy_from_slice(&header);
+
+        let (params, frames, decoded) = decode(data);
+        assert_eq!(params.sample_rate, Some(48000));
+        assert_eq!(params.channels.map(|c| c.count()), Some(channels));
+        assert_eq!(params.bits_per_sample, Some(bits));
+        assert_eq!(frames, Some((samples.len() / channels) as u64));
+
+        let shift = 32 - bits;
+        assert!(
+            decoded
+                .iter()
+                .map(|v| v >> shift)
+                .eq(samples.iter().copied()),
+            "{bits} bit with {channels} channels"
+        );
+    }
+
+    #[test]
+    fn sample_sizes() {
+        // 28 bit has no code in the frame header
+        for bits in [8, 12, 16, 20, 24, 28, 32] {
+            for channels in [1, 2, 8] {
+                // two full blocks and a partial one, the tone needs the 5 bit
+                // rice parameters from 24 bit on
+                round_trip(
+                    bits,
+                    channels,
+                    &signal(bits, channels, 2 * BLOCK_SIZE + 123),
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn partial_blocks() {
+        round_trip(16, 2, &signal(16, 2, 1000));
+        round_trip(24, 1, &signal(24, 1, 1));
+        // the last block is full, so nothing is left to finish
+        round_trip(16, 2, &signal(16, 2, BLOCK_SIZE));
+    }
+
+    #[test]
+    fn silence() {
+        round_trip(16, 2, &vec![0; 2 * BLOCK_SIZE]);
+        round_trip(24, 1, &vec![-1; 100]);
+    }
+
+    #[test]
+    fn noise_is_verbatim() {
+        for bits in [8, 16, 24, 32] {
+            round_trip(bits, 2, &noise(bits, 2 * BLOCK_SIZE));
+        }
+    }
+
+    #[test]
+    fn frame_numbers_beyond_one_byte() {
+        // frame 128 and later take two bytes in the header
+        let samples: Vec<i32> = (0..130 * BLOCK_SIZE).map(|v| (v % 64) as i32).collect();
+        round_trip(8, 1, &samples);
+    }
+
+    #[test]
+    fn coded_numbers() {
+        let coded = |value| {
+            let mut writer = BitWriter::default();
+            write_coded_number(&mut writer, value);
+            writer.data
+        };
+
+        assert_eq!(coded(0x7f), [0x7f]);
+        assert_eq!(coded(0x80), [0xc2, 0x80]);
+        assert_eq!(coded(0x7ff), [0xdf, 0xbf]);
+        assert_eq!(coded(0x800), [0xe0, 0xa0, 0x80]);
+        assert_eq!(coded(0xffff), [0xef, 0xbf, 0xbf]);
+        assert_eq!(coded(0x10000), [0xf0, 0x90, 0x80, 0x80]);
+    }
+}
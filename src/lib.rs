@@ -0,0 +1,44 @@
+//! Captures the audio of USB headsets with the usb-sniffer hardware.
+//!
+//! `sniffer` reads the records of the sniffer as a stream of `SnifferEvent`s,
+//! `usb` decodes the packets and groups them into transactions, `control`
+//! reassembles control transfers on top of them and `device` follows the
+//! enumeration of the devices on the bus. None of them need libpipewire, and
+//! neither do the building blocks of the sinks in `flac` and `ring`.
+//!
+//! With the default `pipewire` feature, `capture` runs the whole pipeline
+//! from the sniffer to the pipewire nodes or audio files. `file` is gated as
+//! well, since its formats and channel positions are those of spa.
+
+/// the audio sinks: pipewire nodes, files, raw samples
+#[cfg(feature = "pipewire")]
+pub mod audio;
+/// the event loop from the sniffer to the audio sinks
+#[cfg(feature = "pipewire")]
+pub mod capture;
+/// control transfers and the requests they carry
+pub mod control;
+/// the audio class descriptors of a configuration
+pub mod descriptor;
+/// the devices on the bus and their addresses
+pub mod device;
+/// the speed of the host clock relative to the system clock
+pub mod drift;
+/// WAV and FLAC files
+#[cfg(feature = "pipewire")]
+pub mod file;
+/// a FLAC encoder for the audio files
+pub mod flac;
+/// pcapng files for Wireshark
+pub mod pcap;
+/// copies of the raw sniffer data
+pub mod record;
+/// a lock-free byte queue between the capture and the audio threads
+pub mod ring;
+/// the sniffer hardware and its records
+pub mod sniffer;
+/// the audio streams of a device and their formats
+#[cfg(feature = "pipewire")]
+pub mod stream;
+/// USB packets and transactions
+pub mod usb;
@@ -1,80 +1,20 @@
-mod audio;
-mod sniffer;
-
-use anyhow::Context as _;
 use clap::Parser as _;
 use pipewire::spa;
-use sniffer::Sniffer;
-use tokio::io::AsyncReadExt as _;
-
-struct AudioFrame {
-    data: [u8; sniffer::MAX_DATA_SIZE],
-    start: usize,
-    end: usize,
-}
-
-impl AudioFrame {
-    pub const fn new() -> Self {
-        Self {
-            data: [0u8; sniffer::MAX_DATA_SIZE],
-            start: 0,
-            end: 0,
-        }
-    }
-
-    pub fn slice(&self) -> &[u8] {
-        &self.data[self.start..self.end]
-    }
-
-    pub fn remove_start(&mut self, num: usize) {
-        if num > self.end - self.start {
-            panic!("tried to remove more data than available");
-        }
-
-        self.start += num;
-    }
-
-    pub fn remove_end(&mut self, num: usize) {
-        if num > self.end - self.start {
-            panic!("tried to remove more data than available");
-        }
+use usbaudio_sniffer::{audio, capture, device, file, sniffer, stream};
 
-        self.end -= num;
-    }
-}
-
-struct AudioReceiver {
-    out_frame_received: bool,
-}
-
-impl AudioReceiver {
-    /// returns true, if there is audio data in `frame`.
-    fn usb_frame_received(&mut self, frame: &mut AudioFrame) -> bool {
-        let data = frame.slice();
-
-        if data.len() < 3 {
-            return false;
-        }
+/// the speed given on the command line, None to detect it
+type BusSpeed = Option<sniffer::CaptureSpeed>;
 
-        if data[0] == 0xe1 {
-            self.out_frame_received = true;
-            return false;
-        }
-
-        if self.out_frame_received && data[0] == 0xc3 {
-            frame.remove_start(1);
-            frame.remove_end(2);
-
-            if frame.slice().is_empty() {
-                log::warn!("empty audio data");
-            }
-
-            return true;
+fn parse_speed(speed: &str) -> Result<BusSpeed, std::io::Error> {
+    Ok(Some(match speed {
+        "low" => sniffer::CaptureSpeed::LowSpeed,
+        "full" => sniffer::CaptureSpeed::FullSpeed,
+        "high" => sniffer::CaptureSpeed::HighSpeed,
+        "auto" => return Ok(None),
+        _ => {
+            return Err(std::io::Error::other("invalid bus speed"));
         }
-
-        self.out_frame_received = false;
-        false
-    }
+    }))
 }
 
 fn parse_format(format: &str) -> Result<spa::param::audio::AudioFormat, std::io::Error> {
@@ -158,120 +98,202 @@ fn parse_channel(channel: &str) -> Result<spa::sys::spa_audio_channel, std::io::
     })
 }
 
+fn parse_id(id: &str) -> Result<u16, std::num::ParseIntError> {
+    u16::from_str_radix(id.strip_prefix("0x").unwrap_or(id), 16)
+}
+
+fn parse_output(output: &str) -> Result<audio::Output, std::io::Error> {
+    match output {
+        "pipewire" => return Ok(audio::Output::PipeWire),
+        "null" => return Ok(audio::Output::Null),
+        "-" => return Ok(audio::Output::Stdout),
+        _ => (),
+    }
+
+    let (container, path) = output
+        .split_once(':')
+        .ok_or_else(|| std::io::Error::other("invalid output"))?;
+    Ok(match container {
+        "wav" => audio::Output::File(file::Container::Wav, path.into()),
+        "flac" => audio::Output::File(file::Container::Flac, path.into()),
+        "raw" => audio::Output::Raw(path.into()),
+        _ => {
+            return Err(std::io::Error::other("invalid output"));
+        }
+    })
+}
+
+#[derive(Debug, clap::Subcommand)]
+enum Command {
+    /// list the connected sniffers
+    List,
+}
+
 #[derive(Debug, clap::Parser)]
-#[command(version, about, long_about = None)]
+#[command(version, about, long_about = None, args_conflicts_with_subcommands = true)]
 pub struct Cli {
+    #[command(subcommand)]
+    command: Option<Command>,
+    /// serial number or path (like 1-2.3) of the sniffer to use, see `list`
+    #[arg(long, conflicts_with = "replay")]
+    device: Option<String>,
+    /// speed of the captured bus: low, full, high or auto to detect it from
+    /// the line state
+    #[arg(short, long, value_parser = parse_speed, default_value = "auto")]
+    speed: BusSpeed,
+    /// sample rate until the host sets one, detected from the configuration
+    /// descriptor if omitted
     #[arg(short, long)]
-    rate: u32,
+    rate: Option<u32>,
+    /// sample format, detected from the configuration descriptor if omitted
     #[arg(short, long, value_parser = parse_format)]
-    format: spa::param::audio::AudioFormat,
+    format: Option<spa::param::audio::AudioFormat>,
+    /// channel positions, detected from the configuration descriptor if omitted
     #[arg(short, long, value_delimiter = ',', value_parser = parse_channel)]
     channels: Vec<spa::sys::spa_audio_channel>,
+    /// only capture audio sent to this USB device address
+    #[arg(short, long, value_parser = clap::value_parser!(u8).range(0..=127))]
+    address: Option<u8>,
+    /// only capture audio sent to the device with this vendor ID, in hex
+    #[arg(long, value_parser = parse_id)]
+    vid: Option<u16>,
+    /// only capture audio sent to the device with this product ID, in hex
+    #[arg(long, value_parser = parse_id)]
+    pid: Option<u16>,
+    /// only capture audio sent to this endpoint number
+    #[arg(short, long, value_parser = clap::value_parser!(u8).range(0..=15))]
+    endpoint: Option<u8>,
+    /// sample rate of the microphone until the host sets one, detected from
+    /// the configuration descriptor if omitted
+    #[arg(long)]
+    mic_rate: Option<u32>,
+    /// sample format of the microphone, detected from the configuration descriptor if omitted
+    #[arg(long, value_parser = parse_format)]
+    mic_format: Option<spa::param::audio::AudioFormat>,
+    /// channel positions of the microphone, detected from the configuration descriptor if omitted
+    #[arg(long, value_delimiter = ',', value_parser = parse_channel)]
+    mic_channels: Vec<spa::sys::spa_audio_channel>,
+    /// only capture microphone audio sent by this endpoint number
+    #[arg(long, value_parser = clap::value_parser!(u8).range(0..=15))]
+    mic_endpoint: Option<u8>,
+    /// where the audio goes: pipewire, wav:PATH, flac:PATH, raw:PATH, null or
+    /// `-` for raw samples on stdout. Raw samples stop when the host changes
+    /// the format, since it can't be signalled in them
+    #[arg(long, value_parser = parse_output, default_value = "pipewire")]
+    output: audio::Output,
+    /// where the microphone audio goes, like --output. Not captured if
+    /// omitted and --output is a file
+    #[arg(long, value_parser = parse_output)]
+    mic_output: Option<audio::Output>,
+    /// start a new audio file after this many seconds
+    #[arg(long)]
+    rotate_secs: Option<u64>,
+    /// start a new audio file after this many megabytes
+    #[arg(long)]
+    rotate_mb: Option<u64>,
+    /// drive the pipewire graph with the clock of the USB host instead of
+    /// resampling the audio
+    #[arg(long)]
+    driver: bool,
+    /// audio buffered on top of one pipewire graph cycle, in milliseconds
+    #[arg(long, default_value_t = 10, value_parser = clap::value_parser!(u64).range(0..=1000))]
+    latency_ms: u64,
+    /// apply the volume and mute the host sets on the feature units of the
+    /// device to the pipewire sources
+    #[arg(long)]
+    mirror_volume: bool,
+    /// read the sniffer data from a file recorded with --record instead of the device
+    #[arg(long, conflicts_with = "record")]
+    replay: Option<std::path::PathBuf>,
+    /// write the raw sniffer data to a file
+    #[arg(long)]
+    record: Option<std::path::PathBuf>,
+    /// write all captured USB packets to a pcapng file
+    #[arg(long)]
+    pcap: Option<std::path::PathBuf>,
 }
 
 #[tokio::main(flavor = "current_thread")]
 async fn main() -> anyhow::Result<()> {
-    env_logger::builder().format_timestamp_millis().init();
+    // stdout might carry the audio
+    env_logger::builder()
+        .format_timestamp_millis()
+        .target(env_logger::Target::Stderr)
+        .init();
     let cli = Cli::parse();
     log::debug!("{cli:#?}");
 
-    let (unused_buffers_sender, unused_buffers_receiver) = crossbeam::channel::unbounded();
-    let (ready_buffers_sender, ready_buffers_receiver) = crossbeam::channel::unbounded();
-
-    for _ in 0..16 {
-        unused_buffers_sender
-            .send(Box::new(AudioFrame::new()))
-            .unwrap();
-    }
-
-    let unused_buffers_sender2 = unused_buffers_sender.clone();
-    let ready_buffers_receiver2 = ready_buffers_receiver.clone();
-    std::thread::spawn(move || {
-        audio::run(
-            &cli,
-            unused_buffers_sender2.clone(),
-            ready_buffers_receiver2.clone(),
-        )
-        .unwrap();
-    });
-
-    let mut sniffer = Sniffer::new().await.context("failed to create sniffer")?;
-    sniffer.start().await?;
-
-    let mut reader = sniffer.reader();
-    let mut audio_receiver = AudioReceiver {
-        out_frame_received: false,
-    };
-
-    let mut toggle = false;
-    let mut scratch = [0u8; sniffer::MAX_DATA_SIZE];
-    loop {
-        let mut common_data = [0u8; 3];
-        reader.read_exact(&mut common_data).await?;
-        let common = sniffer::CommonHeader(&common_data);
-        //log::debug!("common: {common:#?}");
-
-        if common.non_zero() {
-            anyhow::bail!("zero flag in header is not zero");
-        }
-        if common.toggle() != toggle {
-            anyhow::bail!(
-                "toggle flag in header is {}, expected {}",
-                common.toggle(),
-                toggle
+    if let Some(Command::List) = cli.command {
+        for di in sniffer::list_devices().await? {
+            println!(
+                "{}: serial {}, bus {}, port {}",
+                sniffer::device_path(&di),
+                di.serial_number().unwrap_or("-"),
+                di.bus_id(),
+                di.port_chain()
+                    .iter()
+                    .map(|p| p.to_string())
+                    .collect::<Vec<_>>()
+                    .join("."),
             );
         }
-        toggle = !toggle;
-
-        if common.is_data() {
-            let mut header_data = [0u8; 4];
-            reader.read_exact(&mut header_data).await?;
-            let header = sniffer::DataHeader(&header_data);
-            //log::debug!("data: {header:#?}");
+        return Ok(());
+    }
 
-            let frame_size: usize = header.size().into();
-            if !(common_data.len() + header_data.len()..=sniffer::MAX_DATA_SIZE)
-                .contains(&frame_size)
-            {
-                anyhow::bail!("bad frame size: {}", frame_size);
-            }
+    // instances capturing different sniffers need distinguishable nodes
+    let node_name = |name: &str| match &cli.device {
+        Some(device) => format!("{name} ({device})"),
+        None => name.to_string(),
+    };
 
-            let data_size: usize = frame_size - common_data.len() - header_data.len();
-            if data_size > 0 {
-                match unused_buffers_receiver.try_recv() {
-                    Ok(mut frame) => {
-                        let usb_data = &mut frame.data[0..data_size];
-                        reader.read_exact(usb_data).await?;
-                        frame.start = 0;
-                        frame.end = data_size;
+    let audio_options = audio::Options {
+        driver: cli.driver,
+        latency: core::time::Duration::from_millis(cli.latency_ms),
+        blocking: cli.replay.is_some(),
+        rotation: file::Rotation {
+            duration: cli.rotate_secs.map(core::time::Duration::from_secs),
+            size: cli.rotate_mb.map(|v| v * 1_000_000),
+        },
+        mirror_volume: cli.mirror_volume,
+    };
+    let mic_output = match (cli.mic_output, &cli.output) {
+        (Some(output), _) => Some(output),
+        (None, audio::Output::PipeWire) => Some(audio::Output::PipeWire),
+        (None, _) => None,
+    };
 
-                        if audio_receiver.usb_frame_received(&mut frame) {
-                            match ready_buffers_sender.try_send(frame) {
-                                Ok(_) => (),
-                                Err(crossbeam::channel::TrySendError::Full(frame)) => {
-                                    log::warn!("failed to send read buffer");
-                                    unused_buffers_sender.send(frame).unwrap();
-                                }
-                                Err(crossbeam::channel::TrySendError::Disconnected(_)) => {
-                                    unimplemented!();
-                                }
-                            }
-                        } else {
-                            unused_buffers_sender.send(frame).unwrap();
-                        }
-                    }
-                    Err(_) => {
-                        log::warn!("failed to allocate, drop");
-                        let usb_data = &mut scratch[0..data_size];
-                        reader.read_exact(usb_data).await?;
-                    }
-                }
-            }
-        } else {
-            let mut header_data = [0u8; 1];
-            reader.read_exact(&mut header_data).await?;
-            let _header = sniffer::StatusHeader(&header_data);
-            //log::debug!("status: {header:#?}");
-        }
-    }
+    capture::run(capture::Config {
+        device: cli.device.clone(),
+        speed: cli.speed,
+        filter: device::DeviceFilter {
+            address: cli.address,
+            vendor_id: cli.vid,
+            product_id: cli.pid,
+        },
+        speaker: capture::StreamConfig {
+            name: node_name("USB Audio Sniffer"),
+            overrides: stream::FormatOverride {
+                rate: cli.rate,
+                format: cli.format,
+                channels: cli.channels,
+            },
+            endpoint: cli.endpoint,
+            output: Some(cli.output),
+        },
+        microphone: capture::StreamConfig {
+            name: node_name("USB Audio Sniffer Microphone"),
+            overrides: stream::FormatOverride {
+                rate: cli.mic_rate,
+                format: cli.mic_format,
+                channels: cli.mic_channels,
+            },
+            endpoint: cli.mic_endpoint,
+            output: mic_output,
+        },
+        audio: audio_options,
+        replay: cli.replay,
+        record: cli.record,
+        pcap: cli.pcap,
+    })
+    .await
 }
@@ -0,0 +1,261 @@
+use std::io::Write as _;
+
+const BLOCK_TYPE_SECTION_HEADER: u32 = 0x0a0d_0d0a;
+const BLOCK_TYPE_INTERFACE_DESCRIPTION: u32 = 0x0000_0001;
+const BLOCK_TYPE_ENHANCED_PACKET: u32 = 0x0000_0006;
+
+const BYTE_ORDER_MAGIC: u32 = 0x1a2b_3c4d;
+
+const OPT_ENDOFOPT: u16 = 0;
+const OPT_COMMENT: u16 = 1;
+const SHB_USERAPPL: u16 = 4;
+const IF_TSRESOL: u16 = 9;
+
+const LINKTYPE_USB_2_0: u16 = 288;
+
+/// Writes the sniffed USB packets to a pcapng file that can be opened with
+/// Wireshark.
+pub struct PcapWriter {
+    file: std::io::BufWriter<std::fs::File>,
+    /// nanoseconds since the unix epoch at the start of the capture
+    start: u64,
+    /// comments for the next packet
+    comments: Vec<String>,
+}
+
+/// appends an option, whose value is truncated to the maximum length.
+fn push_option(body: &mut Vec<u8>, code: u16, value: &[u8]) {
+    let value = &value[..value.len().min(u16::MAX.into())];
+    body.extend_from_slice(&code.to_le_bytes());
+    body.extend_from_slice(&(value.len() as u16).to_le_bytes());
+    body.extend_from_slice(value);
+    body.resize(body.len().next_multiple_of(4), 0);
+}
+
+/// appends `comment` as comment options, split into parts that fit into one.
+fn push_comment(body: &mut Vec<u8>, comment: &str) {
+    let mut rest = comment;
+    while !rest.is_empty() {
+        let mut len = rest.len().min(u16::MAX.into());
+        while !rest.is_char_boundary(len) {
+            len -= 1;
+        }
+
+        push_option(body, OPT_COMMENT, &rest.as_bytes()[..len]);
+        rest = &rest[len..];
+    }
+}
+
+impl PcapWriter {
+    pub fn new(file: std::fs::File) -> std::io::Result<Self> {
+        let start = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_nanos() as u64;
+        let mut writer = Self {
+            file: std::io::BufWriter::new(file),
+            start,
+            comments: Vec::new(),
+        };
+
+        let mut body = Vec::new();
+        body.extend_from_slice(&BYTE_ORDER_MAGIC.to_le_bytes());
+        body.extend_from_slice(&1u16.to_le_bytes());
+        body.extend_from_slice(&0u16.to_le_bytes());
+        // the section length is unknown
+        body.extend_from_slice(&(-1i64).to_le_bytes());
+        let application = concat!(env!("CARGO_PKG_NAME"), " ", env!("CARGO_PKG_VERSION"));
+        push_option(&mut body, SHB_USERAPPL, application.as_bytes());
+        push_option(&mut body, OPT_ENDOFOPT, &[]);
+        writer.write_block(BLOCK_TYPE_SECTION_HEADER, &body)?;
+
+        let mut body = Vec::new();
+        body.extend_from_slice(&LINKTYPE_USB_2_0.to_le_bytes());
+        body.extend_from_slice(&0u16.to_le_bytes());
+        // no snapshot length limit
+        body.extend_from_slice(&0u32.to_le_bytes());
+        // timestamps are in nanoseconds
+        push_option(&mut body, IF_TSRESOL, &[9]);
+        push_option(&mut body, OPT_ENDOFOPT, &[]);
+        writer.write_block(BLOCK_TYPE_INTERFACE_DESCRIPTION, &body)?;
+
+        Ok(writer)
+    }
+
+    fn write_block(&mut self, block_type: u32, body: &[u8]) -> std::io::Result<()> {
+        let padding = body.len().next_multiple_of(4) - body.len();
+        let length = u32::try_from(12 + body.len() + padding).unwrap();
+
+        self.file.write_all(&block_type.to_le_bytes())?;
+        self.file.write_all(&length.to_le_bytes())?;
+        self.file.write_all(body)?;
+        self.file.write_all(&[0; 3][..padding])?;
+        self.file.write_all(&length.to_le_bytes())?;
+        Ok(())
+    }
+
+    /// `timestamp` is the time since the start of the capture in nanoseconds.
+    pub fn write_packet(&mut self, timestamp: u64, data: &[u8]) -> std::io::Result<()> {
+        let timestamp = self.start + timestamp;
+        let length = u32::try_from(data.len()).unwrap();
+
+        let mut body = Vec::with_capacity(data.len() + 32);
+        body.extend_from_slice(&0u32.to_le_bytes());
+        body.extend_from_slice(&((timestamp >> 32) as u32).to_le_bytes());
+        body.extend_from_slice(&(timestamp as u32).to_le_bytes());
+        body.extend_from_slice(&length.to_le_bytes());
+        body.extend_from_slice(&length.to_le_bytes());
+        body.extend_from_slice(data);
+        body.resize(body.len().next_multiple_of(4), 0);
+
+        if !self.comments.is_empty() {
+            for comment in self.comments.drain(..) {
+                push_comment(&mut body, &comment);
+            }
+            push_option(&mut body, OPT_ENDOFOPT, &[]);
+        }
+
+        self.write_block(BLOCK_TYPE_ENHANCED_PACKET, &body)
+    }
+
+    /// Adds a comment to the next packet.
+    ///
+    /// LINKTYPE_USB_2_0 can only carry USB packets, so events of the sniffer
+    /// itself like bus resets are attached to the packet following them.
+    pub fn add_comment(&mut self, comment: &str) {
+        self.comments.push(comment.to_owned());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// code and value of each option of a block
+    type Options<'a> = Vec<(u16, &'a [u8])>;
+
+    /// returns the type and body of each block of a pcapng file.
+    fn blocks(data: &[u8]) -> Vec<(u32, &[u8])> {
+        let mut blocks = Vec::new();
+        let mut rest = data;
+        while !rest.is_empty() {
+            let block_type = u32::from_le_bytes(rest[..4].try_into().unwrap());
+            let length = u32::from_le_bytes(rest[4..8].try_into().unwrap()) as usize;
+            assert_eq!(rest[length - 4..length], rest[4..8]);
+            blocks.push((block_type, &rest[8..length - 4]));
+            rest = &rest[length..];
+        }
+        blocks
+    }
+
+    /// returns the options in `data`.
+    fn options(mut data: &[u8]) -> Options<'_> {
+        let mut options = Vec::new();
+        while !data.is_empty() {
+            let code = u16::from_le_bytes([data[0], data[1]]);
+            let len = usize::from(u16::from_le_bytes([data[2], data[3]]));
+            options.push((code, &data[4..4 + len]));
+            data = &data[(4 + len).next_multiple_of(4)..];
+        }
+        options
+    }
+
+    /// returns the timestamp, data and options of an enhanced packet block.
+    fn packet(body: &[u8]) -> (u64, &[u8], Options<'_>) {
+        let high = u32::from_le_bytes(body[4..8].try_into().unwrap());
+        let low = u32::from_le_bytes(body[8..12].try_into().unwrap());
+        let len = u32::from_le_bytes(body[12..16].try_into().unwrap()) as usize;
+        let end = 20 + len.next_multiple_of(4);
+        (
+            u64::from(high) << 32 | u64::from(low),
+            &body[20..20 + len],
+            options(&body[end..]),
+        )
+    }
+
+    /// writes a pcapng file with `write` and returns its content.
+    fn write(name: &str, write: impl FnOnce(&mut PcapWriter)) -> (u64, Vec<u8>) {
+        let path = std::env::temp_dir().join(format!(
+            "usbaudio-sniffer-{}-{name}.pcapng",
+            std::process::id()
+        ));
+        let mut writer = PcapWriter::new(std::fs::File::create(&path).unwrap()).unwrap();
+        let start = writer.start;
+        write(&mut writer);
+        drop(writer);
+
+        let data = std::fs::read(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        (start, data)
+    }
+
+    #[test]
+    fn header() {
+        let (_, data) = write("header", |_| {});
+        let blocks = blocks(&data);
+        assert_eq!(blocks.len(), 2);
+
+        let (block_type, body) = blocks[0];
+        assert_eq!(block_type, BLOCK_TYPE_SECTION_HEADER);
+        assert_eq!(body[..4], BYTE_ORDER_MAGIC.to_le_bytes());
+        assert_eq!(options(&body[16..])[0].0, SHB_USERAPPL);
+
+        let (block_type, body) = blocks[1];
+        assert_eq!(block_type, BLOCK_TYPE_INTERFACE_DESCRIPTION);
+        assert_eq!(body[..2], LINKTYPE_USB_2_0.to_le_bytes());
+        assert_eq!(
+            options(&body[8..]),
+            [(IF_TSRESOL, &[9][..]), (OPT_ENDOFOPT, &[][..])]
+        );
+    }
+
+    #[test]
+    fn comments() {
+        let (start, data) = write("comments", |writer| {
+            writer.add_comment("bus reset");
+            writer.add_comment("vbus=false");
+            writer.write_packet(1000, &[0xa5, 0x10, 0x2f]).unwrap();
+            writer.write_packet(2000, &[0xd2]).unwrap();
+        });
+        let blocks = blocks(&data);
+        assert_eq!(blocks.len(), 4);
+
+        let (timestamp, data, options) = packet(blocks[2].1);
+        assert_eq!(timestamp, start + 1000);
+        assert_eq!(data, [0xa5, 0x10, 0x2f]);
+        assert_eq!(
+            options,
+            [
+                (OPT_COMMENT, &b"bus reset"[..]),
+                (OPT_COMMENT, &b"vbus=false"[..]),
+                (OPT_ENDOFOPT, &[][..])
+            ]
+        );
+
+        // the comments are only attached to one packet
+        let (timestamp, data, options) = packet(blocks[3].1);
+        assert_eq!(timestamp, start + 2000);
+        assert_eq!(data, [0xd2]);
+        assert!(options.is_empty());
+    }
+
+    #[test]
+    fn long_comment() {
+        let comment = "ä".repeat(40_000);
+        let (_, data) = write("long_comment", |writer| {
+            writer.add_comment(&comment);
+            writer.write_packet(0, &[0xd2]).unwrap();
+        });
+        let blocks = blocks(&data);
+
+        let (_, _, options) = packet(blocks[2].1);
+        assert_eq!(options.len(), 3);
+        let mut parts = String::new();
+        for (code, value) in &options[..2] {
+            assert_eq!(*code, OPT_COMMENT);
+            parts.push_str(std::str::from_utf8(value).unwrap());
+        }
+        assert_eq!(parts, comment);
+        assert_eq!(options[2], (OPT_ENDOFOPT, &[][..]));
+    }
+}
@@ -0,0 +1,135 @@
+use std::io::Write as _;
+use std::pin::Pin;
+use std::task::{Context, Poll, ready};
+use tokio::io::{AsyncRead, ReadBuf};
+
+/// Passes through everything read from `inner` and writes a copy of it to
+/// `file`, so the session can be replayed later.
+///
+/// The copy is written by a thread of its own, so a slow disk doesn't stall
+/// the runtime. Dropping the recorder waits for it, so a recorder created
+/// afterwards continues behind the data of this one.
+pub struct Recorder<R> {
+    inner: R,
+    data: Option<std::sync::mpsc::Sender<Vec<u8>>>,
+    thread: Option<std::thread::JoinHandle<std::io::Result<()>>>,
+}
+
+impl<R> Recorder<R> {
+    pub fn new(inner: R, file: std::fs::File) -> Self {
+        let (data, received) = std::sync::mpsc::channel::<Vec<u8>>();
+        let thread = std::thread::spawn(move || {
+            let mut file = std::io::BufWriter::new(file);
+            for data in received {
+                file.write_all(&data)?;
+            }
+            file.flush()
+        });
+
+        Self {
+            inner,
+            data: Some(data),
+            thread: Some(thread),
+        }
+    }
+
+    /// waits until everything sent to the thread was written, and returns
+    /// the error it stopped with.
+    fn stop(&mut self) -> std::io::Result<()> {
+        self.data = None;
+        match self.thread.take().map(|t| t.join()) {
+            Some(Ok(result)) => result,
+            Some(Err(_)) => Err(std::io::Error::other("the record thread panicked")),
+            None => Ok(()),
+        }
+    }
+}
+
+impl<R> Drop for Recorder<R> {
+    fn drop(&mut self) {
+        if let Err(e) = self.stop() {
+            log::error!("failed to write the recording: {e}");
+        }
+    }
+}
+
+impl<R: AsyncRead + Unpin> AsyncRead for Recorder<R> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        if self.data.is_none() {
+            return Poll::Ready(Err(std::io::Error::other("the recording stopped")));
+        }
+
+        let start = buf.filled().len();
+        ready!(Pin::new(&mut self.inner).poll_read(cx, buf))?;
+        let data = &buf.filled()[start..];
+        if data.is_empty() {
+            return Poll::Ready(Ok(()));
+        }
+
+        // the thread only stops early if writing failed
+        if let Some(sender) = &self.data
+            && sender.send(data.to_vec()).is_err()
+        {
+            let e = self
+                .stop()
+                .err()
+                .unwrap_or_else(|| std::io::Error::other("the recording stopped"));
+            return Poll::Ready(Err(e));
+        }
+
+        Poll::Ready(Ok(()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::AsyncReadExt as _;
+
+    fn temp_path(name: &str) -> std::path::PathBuf {
+        std::env::temp_dir().join(format!("usbaudio-sniffer-{}-{name}", std::process::id()))
+    }
+
+    #[tokio::test]
+    async fn copy() {
+        let path = temp_path("record.bin");
+        let data: Vec<u8> = (0..100_000u32).map(|v| v as u8).collect();
+
+        let file = std::fs::File::create(&path).unwrap();
+        let mut recorder = Recorder::new(&data[..], file.try_clone().unwrap());
+        let mut read = Vec::new();
+        recorder.read_to_end(&mut read).await.unwrap();
+        drop(recorder);
+        assert_eq!(read, data);
+
+        // a recorder of a reconnected sniffer continues the file
+        let mut recorder = Recorder::new(&data[..10], file);
+        recorder.read_to_end(&mut read).await.unwrap();
+        drop(recorder);
+
+        let recorded = std::fs::read(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(recorded.len(), data.len() + 10);
+        assert_eq!(recorded[..data.len()], data);
+        assert_eq!(recorded[data.len()..], data[..10]);
+    }
+
+    #[tokio::test]
+    async fn write_error() {
+        let path = temp_path("record-read-only.bin");
+        std::fs::File::create(&path).unwrap();
+        // writing to a file opened for reading fails
+        let file = std::fs::File::open(&path).unwrap();
+
+        let mut recorder = Recorder::new(tokio::io::repeat(0), file);
+        let mut buf = vec![0; 64 * 1024];
+        while recorder.read(&mut buf).await.is_ok() {}
+        assert!(recorder.read(&mut buf).await.is_err());
+
+        std::fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,269 @@
+use std::cell::UnsafeCell;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+/// Ring buffer of bytes, which is shared by exactly one producer and one
+/// consumer without locks.
+struct Ring {
+    data: Box<[UnsafeCell<u8>]>,
+    /// number of bytes written so far, only changed by the producer
+    head: AtomicUsize,
+    /// number of bytes read so far, only changed by the consumer
+    tail: AtomicUsize,
+}
+
+// SAFETY: the producer only writes the free bytes and publishes them by
+// advancing `head`, the consumer only reads the published ones and frees them
+// by advancing `tail`.
+unsafe impl Sync for Ring {}
+
+impl Ring {
+    fn len(&self) -> usize {
+        self.head.load(Ordering::Acquire) - self.tail.load(Ordering::Acquire)
+    }
+
+    fn ptr(&self, index: usize) -> *mut u8 {
+        UnsafeCell::raw_get(self.data.as_ptr().wrapping_add(index))
+    }
+}
+
+/// returns both ends of a ring buffer with room for `capacity` bytes.
+pub fn ring(capacity: usize) -> (Producer, Consumer) {
+    let ring = Arc::new(Ring {
+        data: (0..capacity).map(|_| UnsafeCell::new(0)).collect(),
+        head: AtomicUsize::new(0),
+        tail: AtomicUsize::new(0),
+    });
+
+    (Producer { ring: ring.clone() }, Consumer { ring })
+}
+
+/// The writing end of a ring buffer.
+pub struct Producer {
+    ring: Arc<Ring>,
+}
+
+impl Producer {
+    /// returns the number of bytes that haven't been read yet.
+    pub fn len(&self) -> usize {
+        self.ring.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// appends all of `data`, or nothing if it doesn't fit.
+    pub fn push(&mut self, data: &[u8]) -> bool {
+        let capacity = self.ring.data.len();
+        let head = self.ring.head.load(Ordering::Relaxed);
+        let tail = self.ring.tail.load(Ordering::Acquire);
+        if capacity - (head - tail) < data.len() {
+            return false;
+        }
+
+        let start = head % capacity;
+        let first = data.len().min(capacity - start);
+        // SAFETY: the bytes are free, so the consumer doesn't access them
+        unsafe {
+            std::ptr::copy_nonoverlapping(data.as_ptr(), self.ring.ptr(start), first);
+            std::ptr::copy_nonoverlapping(
+                data[first..].as_ptr(),
+                self.ring.ptr(0),
+                data.len() - first,
+            );
+        }
+
+        self.ring.head.store(head + data.len(), Ordering::Release);
+        true
+    }
+}
+
+/// The reading end of a ring buffer.
+pub struct Consumer {
+    ring: Arc<Ring>,
+}
+
+impl Consumer {
+    /// returns the number of bytes that can be read.
+    pub fn len(&self) -> usize {
+        self.ring.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// moves as many bytes as available to the start of `out` and returns
+    /// their number.
+    pub fn pop(&mut self, out: &mut [u8]) -> usize {
+        let capacity = self.ring.data.len();
+        let tail = self.ring.tail.load(Ordering::Relaxed);
+        let head = self.ring.head.load(Ordering::Acquire);
+        let len = (head - tail).min(out.len());
+
+        let start = tail % capacity;
+        let first = len.min(capacity - start);
+        // SAFETY: the bytes were published, so the producer doesn't access them
+        unsafe {
+            std::ptr::copy_nonoverlapping(self.ring.ptr(start), out.as_mut_ptr(), first);
+            std::ptr::copy_nonoverlapping(self.ring.ptr(0), out[first..].as_mut_ptr(), len - first);
+        }
+
+        self.ring.tail.store(tail + len, Ordering::Release);
+        len
+    }
+
+    /// discards all bytes that haven't been read yet.
+    pub fn clear(&mut self) {
+        let head = self.ring.head.load(Ordering::Acquire);
+        self.ring.tail.store(head, Ordering::Release);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn empty() {
+        let (producer, mut consumer) = ring(8);
+        let mut out = [0; 4];
+        assert_eq!(consumer.pop(&mut out), 0);
+        assert_eq!(consumer.len(), 0);
+        assert_eq!(producer.len(), 0);
+    }
+
+    #[test]
+    fn full() {
+        let (mut producer, mut consumer) = ring(8);
+        assert!(producer.push(&[1, 2, 3, 4, 5]));
+        assert!(!producer.push(&[6, 7, 8, 9]));
+        assert!(producer.push(&[6, 7, 8]));
+        assert!(!producer.push(&[9]));
+        assert_eq!(producer.len(), 8);
+        // nothing was written by the failed pushes
+        assert!(producer.push(&[]));
+
+        let mut out = [0; 16];
+        assert_eq!(consumer.pop(&mut out), 8);
+        assert_eq!(out[..8], [1, 2, 3, 4, 5, 6, 7, 8]);
+    }
+
+    #[test]
+    fn wraparound() {
+        let (mut producer, mut consumer) = ring(8);
+        let mut out = [0; 8];
+        for start in 0..20u8 {
+            let data = [start, start + 1, start + 2, start + 3, start + 4];
+            assert!(producer.push(&data));
+
+            // in two parts, so the read position moves as well
+            assert_eq!(consumer.pop(&mut out[..3]), 3);
+            assert_eq!(consumer.pop(&mut out[3..]), 2);
+            assert_eq!(out[..5], data);
+        }
+    }
+
+    #[test]
+    fn clear() {
+        let (mut producer, mut consumer) = ring(8);
+        assert!(producer.push(&[1, 2, 3, 4, 5, 6]));
+        consumer.clear();
+        assert_eq!(consumer.len(), 0);
+
+        // the space is free again
+        assert!(producer.push(&[7, 8, 9, 10, 11, 12, 13, 14]));
+        let mut out = [0; 8];
+        assert_eq!(consumer.pop(&mut out), 8);
+        assert_eq!(out, [7, 8, 9, 10, 11, 12, 13, 14]);
+    }
+
+    /// passes a counter through a small ring, in chunks of varying sizes.
+    #[test]
+    fn two_threads() {
+        const COUNT: usize = 100_000;
+
+        let (mut producer, mut consumer) = ring(61);
+        let thread = std::thread::spawn(move || {
+            let mut next = 0;
+            let mut size = 1;
+            while next < COUNT {
+                let len = size.min(COUNT - next);
+                let data: Vec<u8> = (next..next + len).map(|v| v as u8).collect();
+                if producer.push(&data) {
+                    next += len;
+                    size = size % 23 + 1;
+                } else {
+                    std::thread::yield_now();
+                }
+            }
+        });
+
+        let mut next = 0;
+        let mut out = [0; 37];
+        let mut size = 1;
+        while next < COUNT {
+            let len = consumer.pop(&mut out[..size]);
+            for byte in &out[..len] {
+                assert_eq!(*byte, next as u8);
+                next += 1;
+            }
+            size = size % out.len() + 1;
+            if len == 0 {
+                std::thread::yield_now();
+            }
+        }
+
+        thread.join().unwrap();
+        assert_eq!(consumer.len(), 0);
+    }
+
+    /// clears the ring while the producer writes into it, which has to leave
+    /// only whole chunks.
+    #[test]
+    fn clear_while_writing() {
+        const COUNT: u32 = 20_000;
+
+        let (mut producer, mut consumer) = ring(64);
+        let thread = std::thread::spawn(move || {
+            let mut next = 0;
+            while next < COUNT {
+                if producer.push(&[next.to_le_bytes(), next.to_le_bytes()].concat()) {
+                    next += 1;
+                } else {
+                    std::thread::yield_now();
+                }
+            }
+        });
+
+        let mut last = None;
+        let mut out = [0; 24];
+        let mut iteration = 0u32;
+        while last != Some(COUNT - 1) {
+            iteration += 1;
+            if iteration.is_multiple_of(7) {
+                consumer.clear();
+                continue;
+            }
+
+            let len = consumer.pop(&mut out);
+            assert_eq!(len % 8, 0);
+            for chunk in out[..len].chunks_exact(8) {
+                assert_eq!(chunk[..4], chunk[4..]);
+                let value = u32::from_le_bytes([chunk[0], chunk[1], chunk[2], chunk[3]]);
+                assert!(last.is_none_or(|v| value > v));
+                last = Some(value);
+            }
+
+            if thread.is_finished() && consumer.is_empty() {
+                break;
+            }
+            if len == 0 {
+                std::thread::yield_now();
+            }
+        }
+
+        thread.join().unwrap();
+    }
+}
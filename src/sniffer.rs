@@ -1,4 +1,6 @@
 use anyhow::Context as _;
+use futures::StreamExt as _;
+use tokio::io::AsyncReadExt as _;
 
 #[repr(u16)]
 #[derive(Clone, Copy, Debug)]
@@ -10,10 +12,9 @@ enum CaptureControl {
     Test = 4,
 }
 
-#[allow(dead_code)]
 #[repr(u8)]
-#[derive(Clone, Copy, Debug)]
-enum CaptureSpeed {
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum CaptureSpeed {
     LowSpeed = 0,
     FullSpeed = 1,
     HighSpeed = 2,
@@ -53,6 +54,9 @@ bitfield::bitfield! {
     pub u16, duration, _: 31, 16;
 }
 
+const VENDOR_ID: u16 = 0x6666;
+const PRODUCT_ID: u16 = 0x6620;
+
 const DATA_ENDPOINT_SIZE: usize = 512;
 //const TRANSFER_SIZE: usize = DATA_ENDPOINT_SIZE * 2000;
 const TRANSFER_SIZE: usize = DATA_ENDPOINT_SIZE;
@@ -63,18 +67,651 @@ const TRANSFER_COUNT: usize = 128;
 
 pub const MAX_DATA_SIZE: usize = 1280;
 
-pub struct Sniffer {
+/// number of consecutive valid records required to resynchronize
+const SYNC_RECORDS: usize = 8;
+const READ_SIZE: usize = 4096;
+
+/// frequency of the timestamp counter, which runs at the ULPI clock
+pub const TIMESTAMP_FREQUENCY: u64 = 60_000_000;
+
+impl CaptureSpeed {
+    fn from_bits(bits: u8) -> Self {
+        match bits & 0x3 {
+            0 => Self::LowSpeed,
+            1 => Self::FullSpeed,
+            2 => Self::HighSpeed,
+            _ => Self::Reset,
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::LowSpeed => "low",
+            Self::FullSpeed => "full",
+            Self::HighSpeed => "high",
+            Self::Reset => "reset",
+        }
+    }
+}
+
+/// A USB packet captured by the sniffer.
+#[derive(Clone, Debug)]
+pub struct Packet {
+    /// time since the capture was started, in nanoseconds
+    pub timestamp: u64,
+    /// duration of the packet as reported by the hardware
+    pub duration: u16,
+    /// the hardware detected a CRC error
+    pub crc_error: bool,
+    /// the hardware detected a data error, e.g. a bit stuffing violation
+    pub data_error: bool,
+    /// the packet was longer than the buffer of the hardware
+    pub overflow: bool,
+    /// the packet, starting with the PID
+    pub data: Vec<u8>,
+}
+
+/// The state of the captured bus.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct BusState {
+    /// the speed of the bus, or `Reset` while the host resets it
+    pub speed: CaptureSpeed,
+    pub trigger: bool,
+    pub vbus: bool,
+    /// the raw line state bits
+    pub line_state: u8,
+}
+
+impl BusState {
+    pub fn is_bus_reset(&self) -> bool {
+        self.speed == CaptureSpeed::Reset
+    }
+}
+
+/// A state of the bus reported by the sniffer.
+#[derive(Clone, Copy, Debug)]
+pub struct Status {
+    /// time since the capture was started, in nanoseconds
+    pub timestamp: u64,
+    pub state: BusState,
+}
+
+/// A record of the sniffer, decoded and with a full timestamp.
+#[derive(Clone, Debug)]
+pub enum SnifferEvent {
+    Data(Packet),
+    Status(Status),
+}
+
+impl SnifferEvent {
+    /// returns the time since the capture was started, in nanoseconds.
+    pub fn timestamp(&self) -> u64 {
+        match self {
+            Self::Data(packet) => packet.timestamp,
+            Self::Status(status) => status.timestamp,
+        }
+    }
+}
+
+pub enum RecordBody<'a> {
+    Data(DataHeader<&'a [u8]>, &'a [u8]),
+    Status(StatusHeader<&'a [u8]>),
+}
+
+pub struct Record<'a> {
+    pub common: CommonHeader<&'a [u8]>,
+    pub body: RecordBody<'a>,
+}
+
+enum Check {
+    Valid(usize),
+    Invalid(String),
+    /// the record is incomplete, at least this many bytes are needed
+    Incomplete(usize),
+}
+
+/// Splits the data of the sniffer into records.
+///
+/// Data can get lost or corrupted on the way from the sniffer, e.g. when the
+/// host doesn't read it fast enough. If that happens, the reader scans
+/// forward until it finds a sequence of valid records, instead of aborting.
+pub struct RecordReader<R> {
+    inner: R,
+    buffer: Vec<u8>,
+    /// offset of the next record in `buffer`
+    start: usize,
+    toggle: bool,
+}
+
+impl<R: tokio::io::AsyncRead + Unpin> RecordReader<R> {
+    pub fn new(inner: R) -> Self {
+        Self {
+            inner,
+            buffer: Vec::new(),
+            start: 0,
+            toggle: false,
+        }
+    }
+
+    /// continues with the data of a restarted capture.
+    pub fn reset(&mut self, inner: R) {
+        *self = Self::new(inner);
+    }
+
+    /// continues with a capture that was restarted on the same reader, and
+    /// drops what's left of the previous one.
+    pub fn restart(&mut self) {
+        self.buffer.clear();
+        self.start = 0;
+        self.toggle = false;
+    }
+
+    /// makes sure that at least `len` bytes are available after `start`.
+    async fn fill(&mut self, len: usize) -> std::io::Result<()> {
+        if self.buffer.len() - self.start >= len {
+            return Ok(());
+        }
+
+        self.buffer.drain(..self.start);
+        self.start = 0;
+
+        while self.buffer.len() < len {
+            let end = self.buffer.len();
+            self.buffer.resize(end + READ_SIZE, 0);
+            let read = self.inner.read(&mut self.buffer[end..]).await?;
+            self.buffer.truncate(end + read);
+
+            if read == 0 {
+                return Err(std::io::ErrorKind::UnexpectedEof.into());
+            }
+        }
+
+        Ok(())
+    }
+
+    /// checks the record at `offset` bytes after `start`.
+    fn check(&self, offset: usize, toggle: bool) -> Check {
+        let data = &self.buffer[self.start + offset..];
+        if data.len() < 3 {
+            return Check::Incomplete(offset + 3);
+        }
+
+        let common = CommonHeader(&data[..3]);
+        if common.non_zero() {
+            return Check::Invalid("zero flag in header is not zero".to_string());
+        }
+        if common.toggle() != toggle {
+            return Check::Invalid(format!(
+                "toggle flag in header is {}, expected {toggle}",
+                common.toggle()
+            ));
+        }
+
+        let len = if common.is_data() {
+            if data.len() < 7 {
+                return Check::Incomplete(offset + 7);
+            }
+
+            let len: usize = DataHeader(&data[3..7]).size().into();
+            if !(7..=MAX_DATA_SIZE).contains(&len) {
+                return Check::Invalid(format!("bad frame size: {len}"));
+            }
+            len
+        } else {
+            4
+        };
+
+        if data.len() < len {
+            Check::Incomplete(offset + len)
+        } else {
+            Check::Valid(len)
+        }
+    }
+
+    /// returns the length of the record at `offset`, or why it's invalid.
+    async fn check_filled(
+        &mut self,
+        offset: usize,
+        toggle: bool,
+    ) -> std::io::Result<Result<usize, String>> {
+        loop {
+            match self.check(offset, toggle) {
+                Check::Valid(len) => return Ok(Ok(len)),
+                Check::Invalid(e) => return Ok(Err(e)),
+                Check::Incomplete(len) => self.fill(len).await?,
+            }
+        }
+    }
+
+    /// returns true, if `SYNC_RECORDS` valid records start at `start`.
+    async fn is_synchronized(&mut self) -> std::io::Result<bool> {
+        self.fill(3).await?;
+        let mut toggle = CommonHeader(&self.buffer[self.start..]).toggle();
+        let mut offset = 0;
+
+        for _ in 0..SYNC_RECORDS {
+            match self.check_filled(offset, toggle).await? {
+                Ok(len) => offset += len,
+                Err(_) => return Ok(false),
+            }
+            toggle = !toggle;
+        }
+
+        Ok(true)
+    }
+
+    async fn resynchronize(&mut self) -> std::io::Result<()> {
+        let mut skipped = 0;
+        loop {
+            self.start += 1;
+            skipped += 1;
+
+            if self.is_synchronized().await? {
+                break;
+            }
+        }
+
+        self.toggle = CommonHeader(&self.buffer[self.start..]).toggle();
+        log::warn!("skipped {skipped} bytes to resynchronize");
+        Ok(())
+    }
+
+    pub async fn next(&mut self) -> std::io::Result<Record<'_>> {
+        let len = loop {
+            match self.check_filled(0, self.toggle).await? {
+                Ok(len) => break len,
+                Err(e) => {
+                    log::error!("{e}, resynchronizing");
+                    self.resynchronize().await?;
+                }
+            }
+        };
+
+        self.toggle = !self.toggle;
+        let data = &self.buffer[self.start..self.start + len];
+        self.start += len;
+
+        let common = CommonHeader(&data[..3]);
+        let body = if common.is_data() {
+            RecordBody::Data(DataHeader(&data[3..7]), &data[7..])
+        } else {
+            RecordBody::Status(StatusHeader(&data[3..4]))
+        };
+
+        Ok(Record { common, body })
+    }
+}
+
+/// Turns the data read from the sniffer or a recording into events.
+pub struct EventReader<R> {
+    records: RecordReader<R>,
+    clock: Clock,
+    /// added to the timestamps, so they continue after the ones of the
+    /// previous captures
+    offset: u64,
+    /// timestamp of the last event
+    last: u64,
+}
+
+impl<R: tokio::io::AsyncRead + Unpin> EventReader<R> {
+    pub fn new(inner: R) -> Self {
+        Self {
+            records: RecordReader::new(inner),
+            clock: Clock::default(),
+            offset: 0,
+            last: 0,
+        }
+    }
+
+    /// continues with the data of a restarted capture, whose timestamps
+    /// start at zero again and are moved behind the ones read so far.
+    pub fn reset(&mut self, inner: R) {
+        self.records.reset(inner);
+        self.clock = Clock::default();
+        self.offset = self.last;
+    }
+
+    /// continues after the capture was restarted with `Control::restart`.
+    pub fn restart(&mut self) {
+        self.records.restart();
+        self.clock = Clock::default();
+        self.offset = self.last;
+    }
+
+    /// returns the next event, or an error of kind `UnexpectedEof` at the end
+    /// of a recording.
+    pub async fn next(&mut self) -> std::io::Result<SnifferEvent> {
+        let record = self.records.next().await?;
+        let timestamp = self.offset + self.clock.update(&record.common);
+        self.last = timestamp;
+
+        Ok(match record.body {
+            RecordBody::Data(header, data) => SnifferEvent::Data(Packet {
+                timestamp,
+                duration: header.duration(),
+                crc_error: header.crc_error(),
+                data_error: header.data_error(),
+                overflow: header.overflow(),
+                data: data.to_vec(),
+            }),
+            RecordBody::Status(header) => SnifferEvent::Status(Status {
+                timestamp,
+                state: BusState {
+                    speed: CaptureSpeed::from_bits(header.speed()),
+                    trigger: header.trigger(),
+                    vbus: header.vbus(),
+                    line_state: header.ls(),
+                },
+            }),
+        })
+    }
+
+    /// returns the events as a stream, which ends with the data or after the
+    /// first error.
+    pub fn into_stream(self) -> impl futures::Stream<Item = std::io::Result<SnifferEvent>> {
+        futures::stream::unfold(Some(self), |reader| async move {
+            let mut reader = reader?;
+            match reader.next().await {
+                Ok(v) => Some((Ok(v), Some(reader))),
+                Err(e) if e.kind() == std::io::ErrorKind::UnexpectedEof => None,
+                Err(e) => Some((Err(e), None)),
+            }
+        })
+    }
+}
+
+/// returns the events of the sniffer data read from `inner`, e.g. a file
+/// written with `record::Recorder`.
+pub fn events<R: tokio::io::AsyncRead + Unpin>(
+    inner: R,
+) -> impl futures::Stream<Item = std::io::Result<SnifferEvent>> {
+    EventReader::new(inner).into_stream()
+}
+
+/// Counts the damaged packets, to judge the quality of the capture.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct ErrorCounters {
+    /// packets with a wrong PID check or CRC
+    pub crc: u64,
+    /// packets the hardware reported a CRC error for
+    pub hardware_crc: u64,
+    /// packets the hardware reported a data error for, e.g. a bit stuffing violation
+    pub hardware_data: u64,
+    /// packets the software and the hardware checks disagree on
+    pub mismatch: u64,
+}
+
+impl ErrorCounters {
+    /// returns true, if `packet` arrived intact.
+    pub fn check(&mut self, packet: &Packet) -> bool {
+        let valid = crate::usb::is_valid(&packet.data);
+
+        if !valid {
+            self.crc += 1;
+        }
+        if packet.crc_error {
+            self.hardware_crc += 1;
+        }
+        if packet.data_error {
+            self.hardware_data += 1;
+        }
+        if valid == packet.crc_error {
+            log::debug!(
+                "CRC check disagrees with the hardware (crc_error={}): {:02x?}",
+                packet.crc_error,
+                packet.data
+            );
+            self.mismatch += 1;
+        }
+
+        valid && !packet.crc_error && !packet.data_error
+    }
+}
+
+const LINE_STATE_J: u8 = 1;
+const LINE_STATE_K: u8 = 2;
+
+/// the hub answers the chirp of a high speed device with J and K states of
+/// 40 to 60 µs each
+const CHIRP_MIN_DURATION: u64 = 20_000;
+const CHIRP_MAX_DURATION: u64 = 100_000;
+
+/// Guesses the speed of the bus from the line states reported after a
+/// device was connected or reset.
+///
+/// Full speed devices idle in J. Low speed devices idle in K, which is only
+/// interrupted by the short SE0 of their keep-alives. High speed devices
+/// answer a reset with a long K chirp, followed by alternating K and J
+/// chirps of the hub.
+///
+/// The detection only runs after the device was connected or reset. A high
+/// speed bus idles in SE0 and a suspended high speed device idles in J, so
+/// only losing VBUS restarts it once the bus runs at high speed.
+pub struct SpeedDetector {
+    speed: CaptureSpeed,
+    armed: bool,
+    /// state of the bus at the last status
+    bus_reset: bool,
+    vbus: bool,
+    line_state: Option<u8>,
+    /// time the current line state was entered at, in nanoseconds
+    since: u64,
+    chirps: u32,
+    long_k: u32,
+}
+
+impl SpeedDetector {
+    pub fn new(speed: CaptureSpeed) -> Self {
+        Self {
+            speed,
+            armed: true,
+            bus_reset: false,
+            vbus: true,
+            line_state: None,
+            since: 0,
+            chirps: 0,
+            long_k: 0,
+        }
+    }
+
+    fn arm(&mut self) {
+        self.armed = true;
+        self.chirps = 0;
+        self.long_k = 0;
+    }
+
+    /// returns the speed the capture has to be switched to, if `status`
+    /// completed the detection.
+    pub fn status_received(&mut self, status: &Status) -> Option<CaptureSpeed> {
+        let state = &status.state;
+        let bus_reset = state.is_bus_reset() && !self.bus_reset;
+        let vbus_lost = !state.vbus && self.vbus;
+        self.bus_reset = state.is_bus_reset();
+        self.vbus = state.vbus;
+
+        // the line state that ended with the reset still belongs to the
+        // previous detection
+        let speed = self.line_state_received(status.timestamp, state.line_state & 0x3);
+        if vbus_lost || (bus_reset && self.speed != CaptureSpeed::HighSpeed) {
+            self.arm();
+        }
+        speed
+    }
+
+    fn line_state_received(&mut self, timestamp: u64, line_state: u8) -> Option<CaptureSpeed> {
+        let Some(previous) = self.line_state.replace(line_state) else {
+            self.since = timestamp;
+            return None;
+        };
+        if previous == line_state {
+            return None;
+        }
+
+        let duration = timestamp.saturating_sub(self.since);
+        self.since = timestamp;
+
+        match previous {
+            LINE_STATE_J | LINE_STATE_K
+                if (CHIRP_MIN_DURATION..CHIRP_MAX_DURATION).contains(&duration) =>
+            {
+                self.chirps += 1
+            }
+            LINE_STATE_K if duration >= CHIRP_MAX_DURATION => self.long_k += 1,
+            _ => (),
+        }
+
+        if !self.armed {
+            return None;
+        }
+
+        let speed = if self.chirps >= 3 {
+            CaptureSpeed::HighSpeed
+        } else if self.long_k >= 2 {
+            CaptureSpeed::LowSpeed
+        } else if previous == LINE_STATE_J && duration >= CHIRP_MAX_DURATION {
+            CaptureSpeed::FullSpeed
+        } else {
+            return None;
+        };
+
+        self.armed = false;
+        if speed == self.speed {
+            return None;
+        }
+
+        self.speed = speed;
+        Some(speed)
+    }
+}
+
+/// Extends the 20 bit timestamps of the headers to 64 bits.
+#[derive(Default)]
+pub struct Clock {
+    base: u64,
+}
+
+impl Clock {
+    /// returns the time of `header` since the capture was started, in nanoseconds.
+    pub fn update<T: AsRef<[u8]>>(&mut self, header: &CommonHeader<T>) -> u64 {
+        if header.timestamp_overflow() {
+            self.base += 1 << 20;
+        }
+
+        let ticks = self.base + u64::from(header.ts());
+        (u128::from(ticks) * 1_000_000_000 / u128::from(TIMESTAMP_FREQUENCY)) as u64
+    }
+}
+
+/// Changes the capture settings, also while the data is being read.
+#[derive(Clone)]
+pub struct Control {
     interface: nusb::Interface,
+}
+
+impl Control {
+    async fn ctrl(&self, index: CaptureControl, value: bool) -> anyhow::Result<()> {
+        self.interface
+            .control_out(
+                nusb::transfer::ControlOut {
+                    control_type: nusb::transfer::ControlType::Vendor,
+                    recipient: nusb::transfer::Recipient::Device,
+                    request: 0xd0,
+                    value: index as u16 | (if value { 1 } else { 0 } << 4),
+                    index: 0,
+                    data: &[],
+                },
+                core::time::Duration::from_millis(1),
+            )
+            .await
+            .with_context(|| format!("failed to send {index:?} request"))
+    }
+
+    /// switches a running capture to `speed`, which restarts it like
+    /// `Sniffer::start`.
+    pub async fn restart(&self, speed: CaptureSpeed) -> anyhow::Result<()> {
+        self.ctrl(CaptureControl::Enable, false).await?;
+        self.ctrl(CaptureControl::Reset, true).await?;
+        self.set_speed(speed).await?;
+        self.ctrl(CaptureControl::Reset, false).await?;
+        self.ctrl(CaptureControl::Enable, true).await
+    }
+
+    async fn set_speed(&self, speed: CaptureSpeed) -> anyhow::Result<()> {
+        let speed_u8 = speed as u8;
+        self.ctrl(CaptureControl::Speed0, (speed_u8 & 1) != 0)
+            .await?;
+        self.ctrl(CaptureControl::Speed1, (speed_u8 & 2) != 0)
+            .await?;
+        Ok(())
+    }
+}
+
+pub struct Sniffer {
+    control: Control,
     ep_in: nusb::Endpoint<nusb::transfer::Bulk, nusb::transfer::In>,
 }
 
+/// returns the location of the device in the USB tree, like `1-2.3`.
+pub fn device_path(di: &nusb::DeviceInfo) -> String {
+    let ports: Vec<_> = di.port_chain().iter().map(|p| p.to_string()).collect();
+    format!("{}-{}", di.bus_id(), ports.join("."))
+}
+
+/// returns true, if `di` is a sniffer selected by `device`, which is either
+/// its serial number or its path.
+fn is_sniffer(di: &nusb::DeviceInfo, device: Option<&str>) -> bool {
+    di.vendor_id() == VENDOR_ID
+        && di.product_id() == PRODUCT_ID
+        && device.is_none_or(|d| di.serial_number() == Some(d) || device_path(di) == d)
+}
+
+/// returns all connected sniffers.
+pub async fn list_devices() -> anyhow::Result<Vec<nusb::DeviceInfo>> {
+    Ok(nusb::list_devices()
+        .await
+        .context("failed to list devices")?
+        .filter(|d| is_sniffer(d, None))
+        .collect())
+}
+
+/// waits until the sniffer selected by `device` is connected.
+pub async fn wait_for_device(device: Option<&str>) -> anyhow::Result<()> {
+    // start watching first, so a device connected in between isn't missed
+    let mut watch = nusb::watch_devices().context("failed to watch for devices")?;
+    if nusb::list_devices()
+        .await
+        .context("failed to list devices")?
+        .any(|d| is_sniffer(&d, device))
+    {
+        return Ok(());
+    }
+
+    while let Some(event) = watch.next().await {
+        if let nusb::hotplug::HotplugEvent::Connected(di) = event
+            && is_sniffer(&di, device)
+        {
+            return Ok(());
+        }
+    }
+
+    anyhow::bail!("stopped receiving hotplug events")
+}
+
 impl Sniffer {
-    pub async fn new() -> anyhow::Result<Self> {
-        let di = nusb::list_devices()
+    /// opens the sniffer selected by `device`, or the first one if there's
+    /// no selection.
+    pub async fn new(device: Option<&str>) -> anyhow::Result<Self> {
+        let mut devices = nusb::list_devices()
             .await
-            .unwrap()
-            .find(|d| d.vendor_id() == 0x6666 && d.product_id() == 0x6620)
-            .context("device should be connected")?;
+            .context("failed to list devices")?
+            .filter(|d| is_sniffer(d, device));
+        let di = devices.next().context("device should be connected")?;
+        if devices.next().is_some() {
+            log::warn!(
+                "multiple sniffers connected, using {}, select one with --device",
+                device_path(&di)
+            );
+        }
         log::debug!("Device info: {di:?}");
 
         let device = di.open().await.context("failed to open device")?;
@@ -86,7 +723,10 @@ impl Sniffer {
             .endpoint::<nusb::transfer::Bulk, nusb::transfer::In>(0x82)
             .context("failed to get endpoint")?;
 
-        let mut sniffer = Self { interface, ep_in };
+        let mut sniffer = Self {
+            control: Control { interface },
+            ep_in,
+        };
         sniffer
             .init()
             .await
@@ -95,16 +735,12 @@ impl Sniffer {
         Ok(sniffer)
     }
 
-    pub async fn start(&mut self) -> anyhow::Result<()> {
+    pub async fn start(&mut self, speed: CaptureSpeed) -> anyhow::Result<()> {
         self.ctrl(CaptureControl::Enable, false).await?;
         self.ctrl(CaptureControl::Reset, true).await?;
         self.flush_data().context("failed to flush data")?;
 
-        let speed_u8 = CaptureSpeed::FullSpeed as u8;
-        self.ctrl(CaptureControl::Speed0, (speed_u8 & 1) != 0)
-            .await?;
-        self.ctrl(CaptureControl::Speed1, (speed_u8 & 2) != 0)
-            .await?;
+        self.control.set_speed(speed).await?;
 
         self.ctrl(CaptureControl::Reset, false).await?;
         self.ctrl(CaptureControl::Enable, true).await?;
@@ -113,20 +749,11 @@ impl Sniffer {
     }
 
     async fn ctrl(&mut self, index: CaptureControl, value: bool) -> anyhow::Result<()> {
-        self.interface
-            .control_out(
-                nusb::transfer::ControlOut {
-                    control_type: nusb::transfer::ControlType::Vendor,
-                    recipient: nusb::transfer::Recipient::Device,
-                    request: 0xd0,
-                    value: index as u16 | (if value { 1 } else { 0 } << 4),
-                    index: 0,
-                    data: &[],
-                },
-                core::time::Duration::from_millis(1),
-            )
-            .await
-            .with_context(|| format!("failed to send {index:?} request"))
+        self.control.ctrl(index, value).await
+    }
+
+    pub fn control(&self) -> Control {
+        self.control.clone()
     }
 
     async fn init(&mut self) -> anyhow::Result<()> {
@@ -166,4 +793,333 @@ impl Sniffer {
             .reader(TRANSFER_SIZE)
             .with_num_transfers(TRANSFER_COUNT)
     }
+
+    /// returns the events of the capture, which has to be started first.
+    ///
+    /// Take a `control` first to change the capture settings later.
+    pub fn events(self) -> impl futures::Stream<Item = std::io::Result<SnifferEvent>> {
+        events(self.reader())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// returns a status record with a full speed bus and VBUS.
+    fn status(toggle: bool, overflow: bool, ticks: u32) -> [u8; 4] {
+        [
+            u8::from(toggle) << 6 | u8::from(overflow) << 4 | (ticks >> 16) as u8 & 0xf,
+            (ticks >> 8) as u8,
+            ticks as u8,
+            (CaptureSpeed::FullSpeed as u8) << 6 | 0x10,
+        ]
+    }
+
+    /// returns a data record carrying `packet`.
+    fn data(toggle: bool, ticks: u32, size: u16, packet: &[u8]) -> Vec<u8> {
+        let mut record = status(toggle, false, ticks)[..3].to_vec();
+        record[0] |= 0x80;
+        record.extend_from_slice(&[(size >> 8) as u8 & 0x7, size as u8, 0xff, 0xff]);
+        record.extend_from_slice(packet);
+        record
+    }
+
+    /// returns `count` status records starting at `0x2020 + first` ticks,
+    /// whose timestamp bytes aren't a valid start of a record.
+    fn statuses(first: u32, count: u32, toggle: bool) -> Vec<u8> {
+        (0..count)
+            .flat_map(|i| status(toggle ^ (i % 2 == 1), false, 0x2020 + first + i))
+            .collect()
+    }
+
+    /// returns the timestamps of `statuses` records.
+    fn status_timestamps(ticks: impl IntoIterator<Item = u32>) -> Vec<u64> {
+        ticks
+            .into_iter()
+            .map(|v| u64::from(0x2020 + v) * 50 / 3)
+            .collect()
+    }
+
+    /// returns the timestamps of the events until the end of `reader`.
+    async fn timestamps(reader: &mut EventReader<&[u8]>) -> Vec<u64> {
+        let mut timestamps = Vec::new();
+        loop {
+            match reader.next().await {
+                Ok(event) => timestamps.push(event.timestamp()),
+                Err(e) if e.kind() == std::io::ErrorKind::UnexpectedEof => return timestamps,
+                Err(e) => panic!("{e}"),
+            }
+        }
+    }
+
+    const SE0: u8 = 0;
+    const J: u8 = LINE_STATE_J;
+    const K: u8 = LINE_STATE_K;
+    const FULL: CaptureSpeed = CaptureSpeed::FullSpeed;
+    const HIGH: CaptureSpeed = CaptureSpeed::HighSpeed;
+    const RESET: CaptureSpeed = CaptureSpeed::Reset;
+
+    /// passes statuses with their times in µs to `detector` and returns the
+    /// detected speeds.
+    fn detect(
+        detector: &mut SpeedDetector,
+        statuses: &[(u64, CaptureSpeed, bool, u8)],
+    ) -> Vec<CaptureSpeed> {
+        statuses
+            .iter()
+            .filter_map(|(time, speed, vbus, line_state)| {
+                detector.status_received(&Status {
+                    timestamp: time * 1000,
+                    state: BusState {
+                        speed: *speed,
+                        trigger: false,
+                        vbus: *vbus,
+                        line_state: *line_state,
+                    },
+                })
+            })
+            .collect()
+    }
+
+    /// the host resets a device that was connected at 0 µs, which answers
+    /// with a chirp, and the hub chirps back.
+    const HIGH_SPEED_CONNECT: &[(u64, CaptureSpeed, bool, u8)] = &[
+        (0, FULL, false, SE0),
+        (10, FULL, true, SE0),
+        // the pull-up of a full speed device
+        (100, FULL, true, J),
+        (100_000, RESET, true, SE0),
+        (102_000, RESET, true, K),
+        (104_000, RESET, true, SE0),
+        (104_010, RESET, true, K),
+        (104_060, RESET, true, J),
+        (104_110, RESET, true, K),
+        (104_160, RESET, true, J),
+        (104_210, RESET, true, K),
+        (110_000, HIGH, true, SE0),
+    ];
+
+    #[test]
+    fn full_speed_connect() {
+        let mut detector = SpeedDetector::new(CaptureSpeed::LowSpeed);
+        let speeds = detect(
+            &mut detector,
+            &[
+                (0, FULL, false, SE0),
+                (10, FULL, true, SE0),
+                (100, FULL, true, J),
+                (100_000, RESET, true, SE0),
+                (110_000, FULL, true, J),
+                // SOF packets
+                (111_000, FULL, true, K),
+                (111_001, FULL, true, SE0),
+                (111_002, FULL, true, J),
+                (112_000, FULL, true, K),
+            ],
+        );
+        assert_eq!(speeds, [CaptureSpeed::FullSpeed]);
+    }
+
+    #[test]
+    fn low_speed_connect() {
+        let mut detector = SpeedDetector::new(CaptureSpeed::FullSpeed);
+        let speeds = detect(
+            &mut detector,
+            &[
+                (0, FULL, false, SE0),
+                (10, FULL, true, SE0),
+                // the pull-up of a low speed device
+                (100, FULL, true, K),
+                (100_000, RESET, true, SE0),
+                (110_000, FULL, true, K),
+                // keep-alives
+                (111_000, FULL, true, SE0),
+                (111_001, FULL, true, K),
+                (112_000, FULL, true, SE0),
+                (112_001, FULL, true, K),
+            ],
+        );
+        assert_eq!(speeds, [CaptureSpeed::LowSpeed]);
+    }
+
+    #[test]
+    fn high_speed_connect() {
+        // the device is connected at full speed until the reset
+        let mut detector = SpeedDetector::new(CaptureSpeed::LowSpeed);
+        let speeds = detect(&mut detector, HIGH_SPEED_CONNECT);
+        assert_eq!(speeds, [CaptureSpeed::FullSpeed, CaptureSpeed::HighSpeed]);
+    }
+
+    #[test]
+    fn high_speed_suspend() {
+        let mut detector = SpeedDetector::new(CaptureSpeed::FullSpeed);
+        detect(&mut detector, HIGH_SPEED_CONNECT);
+
+        let speeds = detect(
+            &mut detector,
+            &[
+                // the device switches to its full speed termination
+                (200_000, HIGH, true, J),
+                // resume by the host
+                (210_000, HIGH, true, K),
+                (230_000, HIGH, true, SE0),
+                (240_000, HIGH, true, J),
+                // the reset of the host restarts the chirps, which stay at
+                // high speed
+                (250_000, RESET, true, SE0),
+                (260_000, HIGH, true, SE0),
+                (300_000, HIGH, true, J),
+                (310_000, HIGH, true, SE0),
+            ],
+        );
+        assert!(speeds.is_empty());
+    }
+
+    #[test]
+    fn high_speed_unplugged() {
+        let mut detector = SpeedDetector::new(CaptureSpeed::FullSpeed);
+        detect(&mut detector, HIGH_SPEED_CONNECT);
+
+        let speeds = detect(
+            &mut detector,
+            &[
+                (200_000, HIGH, false, SE0),
+                (300_000, HIGH, true, SE0),
+                (300_100, HIGH, true, J),
+                (400_000, RESET, true, SE0),
+            ],
+        );
+        assert_eq!(speeds, [CaptureSpeed::FullSpeed]);
+    }
+
+    #[tokio::test]
+    async fn status_event() {
+        let data = status(false, false, 60);
+        let mut reader = EventReader::new(&data[..]);
+        let SnifferEvent::Status(event) = reader.next().await.unwrap() else {
+            panic!("no status event");
+        };
+        assert_eq!(event.timestamp, 1000);
+        assert_eq!(
+            event.state,
+            BusState {
+                speed: CaptureSpeed::FullSpeed,
+                trigger: false,
+                vbus: true,
+                line_state: 0,
+            }
+        );
+    }
+
+    #[tokio::test]
+    async fn timestamp_overflow() {
+        let data = [status(false, false, 0xf_fff0), status(true, true, 0x10)].concat();
+        let mut reader = EventReader::new(&data[..]);
+        assert_eq!(
+            timestamps(&mut reader).await,
+            [0xf_fff0 * 50 / 3, 0x10_0010 * 50 / 3]
+        );
+    }
+
+    #[tokio::test]
+    async fn data_event() {
+        let record = data(false, 0x2020, 10, &[0xa5, 0x10, 0x2f]);
+        let mut reader = EventReader::new(&record[..]);
+        let SnifferEvent::Data(packet) = reader.next().await.unwrap() else {
+            panic!("no data event");
+        };
+        assert_eq!(packet.timestamp, status_timestamps([0])[0]);
+        assert_eq!(packet.data, [0xa5, 0x10, 0x2f]);
+        assert_eq!(packet.duration, 0xffff);
+        assert!(!packet.crc_error && !packet.data_error && !packet.overflow);
+    }
+
+    #[tokio::test]
+    async fn garbage_between_records() {
+        let stream = [
+            statuses(0, 2, false),
+            vec![0xff; 3],
+            statuses(2, SYNC_RECORDS as u32 + 2, false),
+        ]
+        .concat();
+        let mut reader = EventReader::new(&stream[..]);
+        assert_eq!(
+            timestamps(&mut reader).await,
+            status_timestamps(0..SYNC_RECORDS as u32 + 4)
+        );
+    }
+
+    #[tokio::test]
+    async fn flipped_toggle() {
+        let stream = [
+            statuses(0, 3, false),
+            // the toggle flag should be set
+            statuses(3, 1, false),
+            statuses(4, SYNC_RECORDS as u32, true),
+        ]
+        .concat();
+        let mut reader = EventReader::new(&stream[..]);
+        assert_eq!(
+            timestamps(&mut reader).await,
+            status_timestamps((0..3).chain(4..SYNC_RECORDS as u32 + 4))
+        );
+    }
+
+    #[tokio::test]
+    async fn bad_length() {
+        let stream = [
+            statuses(0, 2, false),
+            // shorter than the header
+            data(false, 0x2022, 3, &[0xff; 4]),
+            statuses(3, SYNC_RECORDS as u32, true),
+        ]
+        .concat();
+        let mut reader = EventReader::new(&stream[..]);
+        assert_eq!(
+            timestamps(&mut reader).await,
+            status_timestamps((0..2).chain(3..SYNC_RECORDS as u32 + 3))
+        );
+    }
+
+    #[tokio::test]
+    async fn too_few_records_after_garbage() {
+        let stream = [
+            statuses(0, 2, false),
+            vec![0xff; 3],
+            statuses(2, SYNC_RECORDS as u32 - 1, false),
+        ]
+        .concat();
+        let mut reader = EventReader::new(&stream[..]);
+        assert_eq!(timestamps(&mut reader).await, status_timestamps(0..2));
+    }
+
+    #[tokio::test]
+    async fn timestamps_continue_after_reset() {
+        let first = [status(false, false, 600), status(true, true, 60_000)].concat();
+        let mut reader = EventReader::new(&first[..]);
+        let end = (1 << 20) * 50 / 3 + 1_000_000;
+        assert_eq!(timestamps(&mut reader).await, [10_000, end]);
+
+        // the toggle flag and the clock of the new capture start from the
+        // beginning
+        let second = [status(false, false, 60), status(true, false, 120)].concat();
+        reader.reset(&second[..]);
+        assert_eq!(timestamps(&mut reader).await, [end + 1000, end + 2000]);
+    }
+
+    #[tokio::test]
+    async fn timestamps_continue_after_restart() {
+        use tokio::io::AsyncReadExt as _;
+
+        let first = [status(false, false, 600), status(true, false, 1200)].concat();
+        let second = [status(false, false, 60), status(true, false, 120)].concat();
+        let mut reader = EventReader::new(first[..].chain(&second[..]));
+        assert_eq!(reader.next().await.unwrap().timestamp(), 10_000);
+        assert_eq!(reader.next().await.unwrap().timestamp(), 20_000);
+
+        reader.restart();
+        assert_eq!(reader.next().await.unwrap().timestamp(), 21_000);
+        assert_eq!(reader.next().await.unwrap().timestamp(), 22_000);
+    }
 }
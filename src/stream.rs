@@ -0,0 +1,1196 @@
+use crate::audio;
+use crate::control;
+use crate::descriptor;
+use crate::device;
+use crate::usb;
+use pipewire::spa;
+use std::collections::HashMap;
+
+/// Extracts the isochronous audio data sent in one direction.
+pub struct AudioReceiver {
+    /// OUT for audio sent to the device, IN for audio sent by it
+    token_pid: usb::Pid,
+    address: Option<u8>,
+    endpoint: Option<u8>,
+    /// the payloads of the previous transactions of a high-bandwidth endpoint
+    /// in the current microframe
+    microframe: Vec<u8>,
+    /// whether one of the transactions in `microframe` was damaged
+    damaged: bool,
+    /// whether the host sent MDATA in the current microframe, so DATA1 or
+    /// DATA2 end it
+    continued: bool,
+    /// the last intact payload, which replaces damaged ones
+    last: Vec<u8>,
+    /// the number of damaged payloads that were replaced
+    concealed: u64,
+}
+
+impl AudioReceiver {
+    pub fn new(token_pid: usb::Pid, address: Option<u8>, endpoint: Option<u8>) -> Self {
+        Self {
+            token_pid,
+            address,
+            endpoint,
+            microframe: Vec::with_capacity(usb::MAX_ISOCHRONOUS_PAYLOAD),
+            damaged: false,
+            continued: false,
+            last: Vec::with_capacity(usb::MAX_ISOCHRONOUS_PAYLOAD),
+            concealed: 0,
+        }
+    }
+
+    /// returns the number of damaged payloads that were replaced.
+    pub fn concealed(&self) -> u64 {
+        self.concealed
+    }
+
+    /// forgets the state of the current transfer and the filter learned from
+    /// the descriptors.
+    pub fn reset(&mut self, address: Option<u8>, endpoint: Option<u8>) {
+        self.address = address;
+        self.endpoint = endpoint;
+        self.drop_microframe();
+        self.last.clear();
+    }
+
+    fn drop_microframe(&mut self) {
+        self.microframe.clear();
+        self.damaged = false;
+        self.continued = false;
+    }
+
+    /// returns true, if the data packet is one of multiple transactions of
+    /// a high-bandwidth endpoint, but not the last one.
+    ///
+    /// The host sends all but the last transaction of a microframe as MDATA,
+    /// while the device counts down from DATA2 or DATA1 to DATA0.
+    fn is_partial(&self, pid: usb::Pid) -> bool {
+        if self.token_pid == usb::Pid::Out {
+            pid == usb::Pid::MData
+        } else {
+            pid == usb::Pid::Data1 || pid == usb::Pid::Data2
+        }
+    }
+
+    /// drops the transactions of an incomplete high-bandwidth microframe.
+    pub fn sof_received(&mut self) {
+        if !self.microframe.is_empty() || self.continued {
+            log::warn!("incomplete high-bandwidth microframe, drop");
+            self.drop_microframe();
+        }
+    }
+
+    /// returns the audio data, if `transaction` completes an audio packet.
+    ///
+    /// Isochronous transfers aren't retried, so a damaged payload is replaced
+    /// by the previous one to avoid a gap in the audio.
+    pub fn transaction_received(&mut self, transaction: &usb::Transaction) -> Option<&[u8]> {
+        let token = transaction.token;
+        // control, bulk and interrupt transactions are acknowledged
+        if token.pid != self.token_pid
+            || token.endpoint == 0
+            || transaction.handshake.is_some()
+            || self.address.is_some_and(|a| a != token.address)
+            || self.endpoint.is_some_and(|e| e != token.endpoint)
+        {
+            return None;
+        }
+        let pid = transaction.data_pid?;
+        // the host only ends a high-bandwidth microframe with DATA1 or DATA2
+        if self.token_pid == usb::Pid::Out
+            && matches!(pid, usb::Pid::Data1 | usb::Pid::Data2)
+            && !self.continued
+        {
+            return None;
+        }
+        let payload = &transaction.payload[..];
+
+        if payload.len() + self.microframe.len() > usb::MAX_ISOCHRONOUS_PAYLOAD {
+            log::warn!("high-bandwidth microframe too long, drop");
+            self.drop_microframe();
+            return None;
+        }
+        self.microframe.extend_from_slice(payload);
+        self.damaged |= transaction.damaged;
+
+        if self.is_partial(pid) {
+            self.continued = true;
+            return None;
+        }
+        self.continued = false;
+
+        if std::mem::take(&mut self.damaged) {
+            self.concealed += 1;
+            log::debug!("conceal damaged audio packet");
+
+            if self.last.is_empty() {
+                self.last.resize(self.microframe.len(), 0);
+            }
+        } else {
+            std::mem::swap(&mut self.last, &mut self.microframe);
+        }
+        self.microframe.clear();
+
+        if self.last.is_empty() {
+            log::warn!("empty audio data");
+        }
+
+        Some(&self.last)
+    }
+}
+
+/// Format values given on the command line, which take precedence over the
+/// descriptors.
+#[derive(Clone, Debug, Default)]
+pub struct FormatOverride {
+    pub rate: Option<u32>,
+    pub format: Option<spa::param::audio::AudioFormat>,
+    pub channels: Vec<spa::sys::spa_audio_channel>,
+}
+
+impl FormatOverride {
+    /// returns the stream format, if it was fully specified.
+    fn stream_format(&self) -> Option<audio::StreamFormat> {
+        if self.channels.is_empty() {
+            return None;
+        }
+
+        Some(audio::StreamFormat {
+            format: self.format?,
+            rate: self.rate?,
+            channels: self.channels.clone(),
+        })
+    }
+}
+
+/// parses the configuration descriptor returned by `transfer`.
+fn parse_configuration(transfer: &control::ControlTransfer) -> Option<descriptor::Configuration> {
+    let data = &transfer.data;
+    let total_length: usize = u16::from_le_bytes([*data.get(2)?, *data.get(3)?]).into();
+    if data.len() < total_length {
+        // the host only requested the header to learn the total length
+        return None;
+    }
+
+    match descriptor::Configuration::parse(&data[..total_length]) {
+        Ok(v) => {
+            log::debug!("configuration of device {}: {v:#?}", transfer.address);
+            Some(v)
+        }
+        Err(e) => {
+            log::warn!("failed to parse configuration descriptor: {e}");
+            None
+        }
+    }
+}
+
+/// The device audio is captured from, as learned from its configuration descriptor.
+struct Device {
+    address: u8,
+    config: descriptor::Configuration,
+    /// the rates UAC2 devices reported for their clock sources
+    sample_rates: HashMap<u8, descriptor::SampleRates>,
+}
+
+/// The mute and volume controls of one channel of a feature unit.
+#[derive(Clone, Copy, Default)]
+struct ChannelControls {
+    mute: bool,
+    /// in dB
+    volume: f32,
+}
+
+/// Forwards the audio of one endpoint to its own sink.
+pub struct AudioStream {
+    name: String,
+    overrides: FormatOverride,
+    /// the endpoint number given on the command line
+    endpoint: Option<u8>,
+    receiver: AudioReceiver,
+    /// the AudioStreaming interface carrying the captured endpoint
+    interface: Option<u8>,
+    /// the alternate setting last selected by the host, or the first one
+    /// with audio until then
+    alternate_setting: u8,
+    /// the rate set by the last sampling frequency request
+    sampling_rate: Option<u32>,
+    /// where the audio goes, or None if the stream isn't captured
+    sink: Option<Box<dyn audio::AudioSink>>,
+    /// whether the sink was opened
+    running: bool,
+    /// the format last passed to the sink
+    format: Option<audio::StreamFormat>,
+    /// whether the host selected an alternate setting with audio
+    active: bool,
+    /// the feature units in the path of the captured endpoint
+    feature_units: Vec<u8>,
+    /// the controls set by the host, by feature unit and channel
+    feature_controls: HashMap<(u8, u8), ChannelControls>,
+}
+
+impl AudioStream {
+    pub fn new(
+        name: String,
+        overrides: FormatOverride,
+        receiver: AudioReceiver,
+        sink: Option<Box<dyn audio::AudioSink>>,
+    ) -> Self {
+        Self {
+            name,
+            overrides,
+            endpoint: receiver.endpoint,
+            receiver,
+            interface: None,
+            alternate_setting: 0,
+            sampling_rate: None,
+            sink,
+            running: false,
+            format: None,
+            active: false,
+            feature_units: Vec::new(),
+            feature_controls: HashMap::new(),
+        }
+    }
+
+    pub fn is_running(&self) -> bool {
+        self.running
+    }
+
+    fn is_input(&self) -> bool {
+        self.receiver.token_pid == usb::Pid::In
+    }
+
+    fn open_sink(&mut self, format: audio::StreamFormat, active: bool) {
+        let Some(sink) = &mut self.sink else {
+            return;
+        };
+
+        log::info!("{} format: {format:?}", self.name);
+        self.format = Some(format.clone());
+        self.active = active;
+
+        match sink.open(format, active) {
+            Ok(()) => self.running = true,
+            Err(e) => self.sink_failed(e),
+        }
+        self.update_volume();
+    }
+
+    /// stops passing on audio for good, e.g. so a file isn't overwritten.
+    fn sink_failed(&mut self, error: anyhow::Error) {
+        log::error!("{}: {error:#}, stop capturing", self.name);
+        self.sink = None;
+        self.running = false;
+    }
+
+    fn set_active(&mut self, active: bool) {
+        self.active = active;
+
+        if let Some(sink) = &mut self.sink {
+            sink.set_active(active);
+        }
+    }
+
+    fn set_format(&mut self, format: audio::StreamFormat) {
+        self.format = Some(format.clone());
+
+        if let Some(sink) = &mut self.sink
+            && let Err(e) = sink.set_format(format)
+        {
+            self.sink_failed(e);
+        }
+        // the number of channels might have changed
+        self.update_volume();
+    }
+
+    /// returns the volume of all feature units in the path of the stream
+    /// combined.
+    fn volume(&self) -> Option<audio::Volume> {
+        let channels = self.format.as_ref()?.channels.len();
+        let controls = |unit: u8, channel: u8| {
+            self.feature_controls
+                .get(&(unit, channel))
+                .copied()
+                .unwrap_or_default()
+        };
+
+        let mut volume = audio::Volume {
+            mute: false,
+            channels: vec![1.0; channels],
+        };
+        for unit in &self.feature_units {
+            let master = controls(*unit, 0);
+            volume.mute |= master.mute;
+
+            for (index, gain) in volume.channels.iter_mut().enumerate() {
+                let channel = controls(*unit, index as u8 + 1);
+                if channel.mute {
+                    *gain = 0.0;
+                } else {
+                    *gain *= 10f32.powf((master.volume + channel.volume) / 20.0);
+                }
+            }
+        }
+
+        Some(volume)
+    }
+
+    fn update_volume(&mut self) {
+        let Some(volume) = self.volume() else {
+            return;
+        };
+
+        if let Some(sink) = &mut self.sink {
+            sink.set_volume(&volume);
+        }
+    }
+
+    fn streaming<'a>(&self, device: &'a Device) -> Option<&'a descriptor::StreamingInterface> {
+        device
+            .config
+            .streaming_interface(self.interface?, self.alternate_setting)
+    }
+
+    fn clock_source(&self, device: &Device) -> Option<u8> {
+        device.config.clock_source(self.streaming(device)?)
+    }
+
+    /// returns the format of the selected alternate setting, with the format
+    /// and channels from the command line taking precedence.
+    ///
+    /// The rate set by the host wins over the one from the command line, which
+    /// only applies until the host switches rates at runtime.
+    fn stream_format(&self, device: &Device) -> Option<audio::StreamFormat> {
+        let streaming = self.streaming(device)?;
+        let channels = if self.overrides.channels.is_empty() {
+            device.config.channel_positions(streaming)
+        } else {
+            self.overrides.channels.clone()
+        };
+        if channels.is_empty() {
+            log::warn!("no channels: {streaming:?}");
+            return None;
+        }
+
+        Some(audio::StreamFormat {
+            format: match self.overrides.format {
+                Some(v) => v,
+                None => streaming.audio_format().or_else(|| {
+                    log::warn!("unsupported audio format: {streaming:?}");
+                    None
+                })?,
+            },
+            rate: match self.sampling_rate.or(self.overrides.rate) {
+                Some(v) => v,
+                None => streaming
+                    .sample_rates
+                    .as_ref()
+                    .or_else(|| device.sample_rates.get(&self.clock_source(device)?))?
+                    .preferred()?,
+            },
+            channels,
+        })
+    }
+
+    /// switches the audio thread to the current format of the device, once
+    /// it's fully known.
+    fn update_format(&mut self, device: &Device) {
+        let Some(format) = self.stream_format(device) else {
+            log::info!(
+                "{}: waiting for the host to set the sampling rate",
+                self.name
+            );
+            return;
+        };
+
+        if self.is_running() {
+            self.set_format(format);
+        } else {
+            self.open_sink(format, self.active);
+        }
+    }
+
+    /// returns true, if `device` has an endpoint for this stream.
+    fn configuration_received(&mut self, device: &Device) -> bool {
+        let Some(streaming) = device.config.streaming_interfaces.iter().find(|s| {
+            (if self.is_input() {
+                s.is_input()
+            } else {
+                s.is_output()
+            }) && self.endpoint.is_none_or(|e| Some(e) == s.endpoint_number())
+        }) else {
+            return false;
+        };
+
+        log::info!(
+            "capturing {} from device {} endpoint {:?}",
+            self.name,
+            device.address,
+            streaming.endpoint_number()
+        );
+        self.receiver.address = Some(device.address);
+        self.receiver.endpoint = streaming.endpoint_number();
+        self.feature_units = device.config.feature_units(streaming);
+        self.feature_controls.clear();
+        self.interface = Some(streaming.interface);
+        self.alternate_setting = streaming.alternate_setting;
+        self.sampling_rate = None;
+
+        // the host selects an alternate setting once it starts streaming
+        if self.is_running() {
+            self.set_active(false);
+        }
+        self.active = false;
+        self.update_format(device);
+
+        true
+    }
+
+    fn interface_selected(&mut self, device: &Device, interface: u8, alternate_setting: u8) {
+        if self.interface != Some(interface) {
+            return;
+        }
+
+        log::debug!("interface {interface} alternate setting {alternate_setting} selected");
+
+        if alternate_setting == 0 {
+            self.set_active(false);
+            return;
+        }
+
+        let Some(streaming) = device
+            .config
+            .streaming_interface(interface, alternate_setting)
+        else {
+            log::warn!("unknown alternate setting {alternate_setting} of interface {interface}");
+            return;
+        };
+
+        self.receiver.endpoint = streaming.endpoint_number();
+        self.alternate_setting = alternate_setting;
+
+        self.active = true;
+        self.update_format(device);
+        self.set_active(true);
+    }
+
+    fn sampling_frequency_set(
+        &mut self,
+        device: Option<&Device>,
+        address: u8,
+        target: control::SamplingFrequencyTarget,
+        rate: u32,
+    ) {
+        let device = device.filter(|d| d.address == address);
+        let is_captured = match target {
+            control::SamplingFrequencyTarget::Endpoint(endpoint) => {
+                (endpoint & 0x80 != 0) == self.is_input()
+                    && self.receiver.address.is_none_or(|a| a == address)
+                    && self.receiver.endpoint.is_none_or(|e| e == endpoint & 0x0f)
+            }
+            control::SamplingFrequencyTarget::Clock(clock) => {
+                device.is_some_and(|d| self.clock_source(d) == Some(clock))
+            }
+        };
+        if !is_captured {
+            return;
+        }
+
+        log::info!("sampling rate of {} ({target:x?}) is {rate}", self.name);
+
+        if device.is_some() {
+            self.sampling_rate = Some(rate);
+        }
+
+        match &self.format {
+            Some(format) => {
+                if format.rate != rate {
+                    let format = audio::StreamFormat {
+                        rate,
+                        ..format.clone()
+                    };
+                    self.set_format(format);
+                }
+            }
+            None => {
+                if let Some(device) = device {
+                    self.update_format(device);
+                }
+            }
+        }
+    }
+
+    fn feature_unit_control_set(
+        &mut self,
+        device: Option<&Device>,
+        address: u8,
+        unit: u8,
+        channel: u8,
+        control: control::FeatureUnitControl,
+    ) {
+        if device.is_none_or(|d| d.address != address) || !self.feature_units.contains(&unit) {
+            return;
+        }
+
+        log::debug!("{}: {control:?} on channel {channel}", self.name);
+        let controls = self.feature_controls.entry((unit, channel)).or_default();
+        match control {
+            control::FeatureUnitControl::Mute(mute) => controls.mute = mute,
+            control::FeatureUnitControl::Volume(volume) => controls.volume = volume,
+        }
+
+        self.update_volume();
+    }
+
+    fn set_drift(&mut self, ratio: f64) {
+        if let Some(sink) = &mut self.sink {
+            sink.set_drift(ratio);
+        }
+    }
+
+    fn report(&mut self) {
+        if let Some(sink) = &mut self.sink {
+            sink.report();
+        }
+    }
+
+    /// pauses the stream until the device was configured again.
+    fn bus_reset(&mut self, address: Option<u8>) {
+        self.receiver.reset(address, self.endpoint);
+        self.interface = None;
+        self.alternate_setting = 0;
+        self.sampling_rate = None;
+        self.feature_units.clear();
+        self.feature_controls.clear();
+        self.update_volume();
+
+        if self.active {
+            self.set_active(false);
+        }
+    }
+
+    fn transaction_received(&mut self, transaction: &usb::Transaction) {
+        if !self.running {
+            return;
+        }
+        let Some(sink) = &mut self.sink else {
+            return;
+        };
+        let Some(audio) = self.receiver.transaction_received(transaction) else {
+            return;
+        };
+
+        if let Err(e) = sink.write(audio) {
+            self.sink_failed(e);
+        }
+    }
+}
+
+/// Follows the control requests of the host to find out where audio is sent
+/// to, in which format and whether it's being streamed at all.
+pub struct StreamControl {
+    /// only follow the device selected by this
+    filter: device::DeviceFilter,
+    devices: device::DeviceTable,
+    device: Option<Device>,
+    speaker: AudioStream,
+    microphone: AudioStream,
+}
+
+impl StreamControl {
+    pub fn new(
+        filter: device::DeviceFilter,
+        speaker: AudioStream,
+        microphone: AudioStream,
+    ) -> Self {
+        let mut stream_control = Self {
+            filter,
+            devices: device::DeviceTable::default(),
+            device: None,
+            speaker,
+            microphone,
+        };
+
+        for stream in [&mut stream_control.speaker, &mut stream_control.microphone] {
+            if let Some(format) = stream.overrides.stream_format() {
+                stream.open_sink(format, true);
+            }
+        }
+
+        stream_control
+    }
+
+    pub fn is_running(&self) -> bool {
+        self.speaker.is_running() || self.microphone.is_running()
+    }
+
+    pub fn transaction_received(&mut self, transaction: &usb::Transaction) {
+        if !self
+            .filter
+            .matches(transaction.token.address, &self.devices)
+        {
+            return;
+        }
+
+        self.speaker.transaction_received(transaction);
+        self.microphone.transaction_received(transaction);
+    }
+
+    pub fn sof_received(&mut self) {
+        self.speaker.receiver.sof_received();
+        self.microphone.receiver.sof_received();
+    }
+
+    /// returns the number of damaged audio packets that were concealed.
+    pub fn concealed(&self) -> u64 {
+        self.speaker.receiver.concealed + self.microphone.receiver.concealed
+    }
+
+    /// forgets everything learned about the device, which is enumerated
+    /// again after it was reset or reconnected.
+    ///
+    /// The pipewire nodes are kept, so clients stay connected.
+    pub fn bus_reset(&mut self) {
+        self.device = None;
+        self.devices.bus_reset();
+
+        for stream in [&mut self.speaker, &mut self.microphone] {
+            stream.bus_reset(self.filter.address);
+        }
+    }
+
+    /// passes the speed of the host clock relative to the system clock to the
+    /// sinks.
+    pub fn set_drift(&mut self, ratio: f64) {
+        self.speaker.set_drift(ratio);
+        self.microphone.set_drift(ratio);
+    }
+
+    /// logs the problems of the sinks since the last report.
+    pub fn report(&mut self) {
+        self.speaker.report();
+        self.microphone.report();
+    }
+
+    pub fn transfer_received(&mut self, transfer: &control::ControlTransfer) {
+        self.devices.transfer_received(transfer);
+        if !self.filter.matches(transfer.address, &self.devices) {
+            return;
+        }
+
+        let request = transfer.request();
+        log::debug!("control transfer to {}: {request:?}", transfer.address);
+
+        match request {
+            control::Request::GetDescriptor {
+                descriptor_type: usb::DESCRIPTOR_TYPE_CONFIGURATION,
+                ..
+            } => self.configuration_received(transfer),
+            control::Request::SetInterface {
+                interface,
+                alternate_setting,
+            } => self.interface_selected(transfer.address, interface, alternate_setting),
+            control::Request::Class { .. } => {
+                if let Some((unit, channel, control)) = transfer.feature_unit_control() {
+                    for stream in [&mut self.speaker, &mut self.microphone] {
+                        stream.feature_unit_control_set(
+                            self.device.as_ref(),
+                            transfer.address,
+                            unit,
+                            channel,
+                            control,
+                        );
+                    }
+                }
+
+                if let Some((target, rate)) = transfer.sampling_frequency() {
+                    for stream in [&mut self.speaker, &mut self.microphone] {
+                        stream.sampling_frequency_set(
+                            self.device.as_ref(),
+                            transfer.address,
+                            target,
+                            rate,
+                        );
+                    }
+                } else if let Some((clock, rates)) = transfer.sampling_frequency_range() {
+                    self.sampling_frequency_range_received(transfer.address, clock, rates);
+                }
+            }
+            _ => (),
+        }
+    }
+
+    fn configuration_received(&mut self, transfer: &control::ControlTransfer) {
+        let Some(config) = parse_configuration(transfer) else {
+            return;
+        };
+
+        let device = Device {
+            address: transfer.address,
+            config,
+            sample_rates: HashMap::new(),
+        };
+
+        let has_speaker = self.speaker.configuration_received(&device);
+        let has_microphone = self.microphone.configuration_received(&device);
+        if !has_speaker && !has_microphone {
+            log::debug!("device {} has no audio endpoint", transfer.address);
+            return;
+        }
+
+        self.device = Some(device);
+    }
+
+    fn interface_selected(&mut self, address: u8, interface: u8, alternate_setting: u8) {
+        let Some(device) = &self.device else {
+            return;
+        };
+        if device.address != address {
+            return;
+        }
+
+        for stream in [&mut self.speaker, &mut self.microphone] {
+            stream.interface_selected(device, interface, alternate_setting);
+        }
+    }
+
+    fn sampling_frequency_range_received(
+        &mut self,
+        address: u8,
+        clock: u8,
+        rates: descriptor::SampleRates,
+    ) {
+        let Some(device) = &mut self.device else {
+            return;
+        };
+        if device.address != address {
+            return;
+        }
+
+        log::debug!("clock source {clock} supports {rates:?}");
+        device.sample_rates.insert(clock, rates);
+
+        for stream in [&mut self.speaker, &mut self.microphone] {
+            if stream.format.is_none() && stream.clock_source(device) == Some(clock) {
+                stream.update_format(device);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn transaction(
+        token_pid: usb::Pid,
+        endpoint: u8,
+        data_pid: usb::Pid,
+        payload: &[u8],
+        handshake: Option<usb::Pid>,
+    ) -> usb::Transaction {
+        usb::Transaction {
+            split: None,
+            token: usb::Token {
+                pid: token_pid,
+                address: 5,
+                endpoint,
+            },
+            data_pid: Some(data_pid),
+            payload: payload.to_vec(),
+            handshake,
+            damaged: false,
+        }
+    }
+
+    fn out(data_pid: usb::Pid, payload: &[u8]) -> usb::Transaction {
+        transaction(usb::Pid::Out, 1, data_pid, payload, None)
+    }
+
+    #[test]
+    fn isochronous_out() {
+        let mut receiver = AudioReceiver::new(usb::Pid::Out, None, None);
+        assert_eq!(
+            receiver.transaction_received(&out(usb::Pid::Data0, &[1, 2])),
+            Some(&[1, 2][..])
+        );
+    }
+
+    #[test]
+    fn control_data_is_ignored() {
+        let mut receiver = AudioReceiver::new(usb::Pid::Out, None, None);
+        let setup = transaction(usb::Pid::Out, 0, usb::Pid::Data1, &[0x80, 0xbb], None);
+        assert_eq!(receiver.transaction_received(&setup), None);
+
+        let acknowledged = transaction(
+            usb::Pid::Out,
+            1,
+            usb::Pid::Data0,
+            &[1, 2],
+            Some(usb::Pid::Ack),
+        );
+        assert_eq!(receiver.transaction_received(&acknowledged), None);
+    }
+
+    #[test]
+    fn high_bandwidth_out() {
+        let mut receiver = AudioReceiver::new(usb::Pid::Out, None, None);
+        // DATA1 without MDATA isn't the end of a high-bandwidth microframe
+        assert_eq!(
+            receiver.transaction_received(&out(usb::Pid::Data1, &[9])),
+            None
+        );
+
+        assert_eq!(
+            receiver.transaction_received(&out(usb::Pid::MData, &[1, 2])),
+            None
+        );
+        assert_eq!(
+            receiver.transaction_received(&out(usb::Pid::MData, &[3])),
+            None
+        );
+        assert_eq!(
+            receiver.transaction_received(&out(usb::Pid::Data2, &[4])),
+            Some(&[1, 2, 3, 4][..])
+        );
+
+        // the next microframe starts over
+        assert_eq!(
+            receiver.transaction_received(&out(usb::Pid::Data2, &[5])),
+            None
+        );
+    }
+
+    #[test]
+    fn incomplete_microframe() {
+        let mut receiver = AudioReceiver::new(usb::Pid::Out, None, None);
+        receiver.transaction_received(&out(usb::Pid::MData, &[1, 2]));
+        receiver.sof_received();
+        assert_eq!(
+            receiver.transaction_received(&out(usb::Pid::Data1, &[3])),
+            None
+        );
+        assert_eq!(
+            receiver.transaction_received(&out(usb::Pid::Data0, &[4])),
+            Some(&[4][..])
+        );
+    }
+
+    #[test]
+    fn damaged_payload_is_concealed() {
+        let mut receiver = AudioReceiver::new(usb::Pid::Out, None, None);
+        receiver.transaction_received(&out(usb::Pid::Data0, &[1, 2]));
+
+        let mut damaged = out(usb::Pid::Data0, &[0xff, 0xff]);
+        damaged.damaged = true;
+        assert_eq!(receiver.transaction_received(&damaged), Some(&[1, 2][..]));
+        assert_eq!(receiver.concealed(), 1);
+    }
+
+    /// a UAC1 speaker with a 16 and a 24 bit alternate setting at 48 kHz
+    const CONFIGURATION: &[&[u8]] = &[
+        &[0x09, 0x02, 0x8f, 0x00, 0x02, 0x01, 0x00, 0x80, 0x32],
+        &[0x09, 0x04, 0x00, 0x00, 0x00, 0x01, 0x01, 0x00, 0x00],
+        &[0x09, 0x24, 0x01, 0x00, 0x01, 0x1e, 0x00, 0x01, 0x01],
+        &[
+            0x0c, 0x24, 0x02, 0x01, 0x01, 0x01, 0x00, 0x02, 0x03, 0x00, 0x00, 0x00,
+        ],
+        &[0x09, 0x24, 0x03, 0x03, 0x01, 0x03, 0x00, 0x01, 0x00],
+        &[0x09, 0x04, 0x01, 0x00, 0x00, 0x01, 0x02, 0x00, 0x00],
+        &[0x09, 0x04, 0x01, 0x01, 0x01, 0x01, 0x02, 0x00, 0x00],
+        &[0x07, 0x24, 0x01, 0x01, 0x01, 0x01, 0x00],
+        &[
+            0x0b, 0x24, 0x02, 0x01, 0x02, 0x02, 0x10, 0x01, 0x80, 0xbb, 0x00,
+        ],
+        &[0x09, 0x05, 0x01, 0x09, 0xc0, 0x00, 0x01, 0x00, 0x00],
+        &[0x07, 0x25, 0x01, 0x01, 0x00, 0x00, 0x00],
+        &[0x09, 0x04, 0x01, 0x02, 0x01, 0x01, 0x02, 0x00, 0x00],
+        &[0x07, 0x24, 0x01, 0x01, 0x01, 0x01, 0x00],
+        &[
+            0x0b, 0x24, 0x02, 0x01, 0x02, 0x03, 0x18, 0x01, 0x80, 0xbb, 0x00,
+        ],
+        &[0x09, 0x05, 0x01, 0x09, 0x20, 0x01, 0x01, 0x00, 0x00],
+        &[0x07, 0x25, 0x01, 0x01, 0x00, 0x00, 0x00],
+    ];
+
+    /// A writer whose data stays readable after it was moved into a sink.
+    #[derive(Clone, Default)]
+    struct SharedWriter(std::rc::Rc<std::cell::RefCell<Vec<u8>>>);
+
+    impl std::io::Write for SharedWriter {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0.borrow_mut().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    /// returns the transactions of a control transfer with its status stage.
+    fn control(setup: [u8; 8], data: &[u8]) -> Vec<usb::Transaction> {
+        let device_to_host = setup[0] & 0x80 != 0;
+        let (data_pid, status_pid) = if device_to_host {
+            (usb::Pid::In, usb::Pid::Out)
+        } else {
+            (usb::Pid::Out, usb::Pid::In)
+        };
+
+        let mut transactions = vec![transaction(
+            usb::Pid::Setup,
+            0,
+            usb::Pid::Data0,
+            &setup,
+            Some(usb::Pid::Ack),
+        )];
+        for chunk in data.chunks(64) {
+            transactions.push(transaction(
+                data_pid,
+                0,
+                usb::Pid::Data1,
+                chunk,
+                Some(usb::Pid::Ack),
+            ));
+        }
+        transactions.push(transaction(
+            status_pid,
+            0,
+            usb::Pid::Data1,
+            &[],
+            Some(usb::Pid::Ack),
+        ));
+        transactions
+    }
+
+    fn set_interface(alternate_setting: u8) -> Vec<usb::Transaction> {
+        control([0x01, 0x0b, alternate_setting, 0, 1, 0, 0, 0], &[])
+    }
+
+    /// passes the transactions through the pipeline like `capture::run` does.
+    fn replay(stream_control: &mut StreamControl, transactions: &[usb::Transaction]) {
+        let mut control_receiver = control::ControlReceiver::default();
+        for transaction in transactions {
+            if let Some(transfer) = control_receiver.transaction_received(transaction) {
+                stream_control.transfer_received(&transfer);
+            }
+            stream_control.transaction_received(transaction);
+            stream_control.sof_received();
+        }
+    }
+
+    fn speaker_to(writer: &SharedWriter) -> StreamControl {
+        speaker(Box::new(audio::RawSink::new(writer.clone())))
+    }
+
+    fn speaker(sink: Box<dyn audio::AudioSink>) -> StreamControl {
+        StreamControl::new(
+            device::DeviceFilter::default(),
+            AudioStream::new(
+                "speaker".to_string(),
+                FormatOverride::default(),
+                AudioReceiver::new(usb::Pid::Out, None, None),
+                Some(sink),
+            ),
+            AudioStream::new(
+                "microphone".to_string(),
+                FormatOverride::default(),
+                AudioReceiver::new(usb::Pid::In, None, None),
+                None,
+            ),
+        )
+    }
+
+    #[test]
+    fn raw_output() {
+        let writer = SharedWriter::default();
+        let mut stream_control = speaker_to(&writer);
+
+        let transactions = [
+            // audio before the device was configured
+            vec![out(usb::Pid::Data0, &[0xee; 4])],
+            control(
+                [0x80, 0x06, 0x00, 0x02, 0x00, 0x00, 0xff, 0x00],
+                &CONFIGURATION.concat(),
+            ),
+            set_interface(1),
+            // SET_CUR of the sampling frequency of endpoint 1 to 48 kHz
+            control(
+                [0x22, 0x01, 0x00, 0x01, 0x01, 0x00, 0x03, 0x00],
+                &[0x80, 0xbb, 0x00],
+            ),
+            vec![
+                out(usb::Pid::Data0, &[1, 2, 3, 4]),
+                // another endpoint
+                transaction(usb::Pid::Out, 2, usb::Pid::Data0, &[0xee; 4], None),
+                out(usb::Pid::Data0, &[5, 6, 7, 8]),
+            ],
+            // the same format again
+            set_interface(0),
+            set_interface(1),
+            vec![out(usb::Pid::Data0, &[9, 10, 11, 12])],
+        ]
+        .concat();
+        replay(&mut stream_control, &transactions);
+
+        assert!(stream_control.is_running());
+        assert_eq!(*writer.0.borrow(), [1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12]);
+    }
+
+    #[test]
+    fn no_channels() {
+        let writer = SharedWriter::default();
+        let mut stream_control = speaker_to(&writer);
+
+        // bNrChannels of both alternate settings is 0
+        let mut configuration = CONFIGURATION.concat();
+        for start in [73, 116] {
+            assert_eq!(configuration[start..start + 4], [0x0b, 0x24, 0x02, 0x01]);
+            configuration[start + 4] = 0;
+        }
+
+        let transactions = [
+            control(
+                [0x80, 0x06, 0x00, 0x02, 0x00, 0x00, 0xff, 0x00],
+                &configuration,
+            ),
+            set_interface(1),
+            control(
+                [0x22, 0x01, 0x00, 0x01, 0x01, 0x00, 0x03, 0x00],
+                &[0x80, 0xbb, 0x00],
+            ),
+            vec![out(usb::Pid::Data0, &[1, 2, 3, 4])],
+        ]
+        .concat();
+        replay(&mut stream_control, &transactions);
+
+        assert!(!stream_control.is_running());
+        assert!(writer.0.borrow().is_empty());
+    }
+
+    #[test]
+    fn raw_output_stops_on_format_change() {
+        let writer = SharedWriter::default();
+        let mut stream_control = speaker_to(&writer);
+
+        let transactions = [
+            control(
+                [0x80, 0x06, 0x00, 0x02, 0x00, 0x00, 0xff, 0x00],
+                &CONFIGURATION.concat(),
+            ),
+            set_interface(1),
+            vec![out(usb::Pid::Data0, &[1, 2, 3, 4])],
+            // 24 bit
+            set_interface(2),
+            vec![out(usb::Pid::Data0, &[5, 6, 7, 8, 9, 10])],
+        ]
+        .concat();
+        replay(&mut stream_control, &transactions);
+
+        assert!(!stream_control.is_running());
+        assert_eq!(*writer.0.borrow(), [1, 2, 3, 4]);
+    }
+
+    /// A sink that keeps the volumes it was set to.
+    #[derive(Clone, Default)]
+    struct VolumeSink(std::rc::Rc<std::cell::RefCell<Vec<audio::Volume>>>);
+
+    impl audio::AudioSink for VolumeSink {
+        fn open(&mut self, _format: audio::StreamFormat, _active: bool) -> anyhow::Result<()> {
+            Ok(())
+        }
+
+        fn write(&mut self, _data: &[u8]) -> anyhow::Result<()> {
+            Ok(())
+        }
+
+        fn set_format(&mut self, _format: audio::StreamFormat) -> anyhow::Result<()> {
+            Ok(())
+        }
+
+        fn set_volume(&mut self, volume: &audio::Volume) {
+            self.0.borrow_mut().push(volume.clone());
+        }
+    }
+
+    /// returns SET_CUR of a feature unit control of interface 0.
+    fn set_feature_unit(selector: u8, channel: u8, unit: u8, data: &[u8]) -> Vec<usb::Transaction> {
+        control(
+            [0x21, 0x01, channel, selector, 0, unit, data.len() as u8, 0],
+            data,
+        )
+    }
+
+    fn assert_volume(volume: &audio::Volume, mute: bool, channels: [f32; 2]) {
+        assert_eq!(volume.mute, mute);
+        assert_eq!(volume.channels.len(), 2);
+        for (gain, expected) in volume.channels.iter().zip(channels) {
+            assert!((gain - expected).abs() < 1e-4, "{volume:?}");
+        }
+    }
+
+    #[test]
+    fn volume() {
+        let sink = VolumeSink::default();
+        let mut stream_control = speaker(Box::new(sink.clone()));
+
+        // feature units 2 and 4 between the USB streaming terminal and the
+        // speaker, and unit 6 on another path
+        let mut configuration = CONFIGURATION.to_vec();
+        configuration[0] = &[0x09, 0x02, 0xad, 0x00, 0x02, 0x01, 0x00, 0x80, 0x32];
+        configuration[4] = &[0x09, 0x24, 0x03, 0x03, 0x01, 0x03, 0x00, 0x04, 0x00];
+        configuration.splice(
+            4..4,
+            [
+                &[0x0a, 0x24, 0x06, 0x02, 0x01, 0x01, 0x01, 0x02, 0x02, 0x00][..],
+                &[0x0a, 0x24, 0x06, 0x04, 0x02, 0x01, 0x01, 0x02, 0x02, 0x00],
+                &[0x0a, 0x24, 0x06, 0x06, 0x07, 0x01, 0x01, 0x02, 0x02, 0x00],
+            ],
+        );
+
+        let transactions = [
+            control(
+                [0x80, 0x06, 0x00, 0x02, 0x00, 0x00, 0xff, 0x00],
+                &configuration.concat(),
+            ),
+            set_interface(1),
+            control(
+                [0x22, 0x01, 0x00, 0x01, 0x01, 0x00, 0x03, 0x00],
+                &[0x80, 0xbb, 0x00],
+            ),
+        ]
+        .concat();
+        replay(&mut stream_control, &transactions);
+        assert_volume(sink.0.borrow().last().unwrap(), false, [1.0, 1.0]);
+
+        // -6 dB on the master channel of unit 2, and -14 dB more on the right
+        // channel of unit 4
+        let transactions = [
+            set_feature_unit(0x02, 0, 2, &[0x00, 0xfa]),
+            set_feature_unit(0x02, 2, 4, &[0x00, 0xf2]),
+        ]
+        .concat();
+        replay(&mut stream_control, &transactions);
+        assert_volume(sink.0.borrow().last().unwrap(), false, [0.5012, 0.1]);
+
+        // units outside of the path of the stream are ignored
+        let count = sink.0.borrow().len();
+        replay(&mut stream_control, &set_feature_unit(0x01, 0, 6, &[1]));
+        assert_eq!(sink.0.borrow().len(), count);
+
+        // muting a channel keeps the other one
+        replay(&mut stream_control, &set_feature_unit(0x01, 1, 4, &[1]));
+        assert_volume(sink.0.borrow().last().unwrap(), false, [0.0, 0.1]);
+
+        replay(&mut stream_control, &set_feature_unit(0x01, 0, 2, &[1]));
+        assert_volume(sink.0.borrow().last().unwrap(), true, [0.0, 0.1]);
+
+        // silence
+        replay(
+            &mut stream_control,
+            &set_feature_unit(0x02, 2, 2, &[0x00, 0x80]),
+        );
+        assert_volume(sink.0.borrow().last().unwrap(), true, [0.0, 0.0]);
+    }
+}
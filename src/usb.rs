@@ -0,0 +1,651 @@
+/// The packet identifier, including its check bits.
+#[repr(u8)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Pid {
+    Out = 0xe1,
+    In = 0x69,
+    Sof = 0xa5,
+    Setup = 0x2d,
+    Data0 = 0xc3,
+    Data1 = 0x4b,
+    Data2 = 0x87,
+    MData = 0x0f,
+    Ack = 0xd2,
+    Nak = 0x5a,
+    Stall = 0x1e,
+    Nyet = 0x96,
+    /// PRE sent by the host, or ERR sent by a hub in a split transaction
+    Pre = 0x3c,
+    Split = 0x78,
+    Ping = 0xb4,
+}
+
+impl Pid {
+    /// returns None, if the check bits don't match or the PID is reserved.
+    pub fn from_byte(byte: u8) -> Option<Self> {
+        Some(match byte {
+            0xe1 => Self::Out,
+            0x69 => Self::In,
+            0xa5 => Self::Sof,
+            0x2d => Self::Setup,
+            0xc3 => Self::Data0,
+            0x4b => Self::Data1,
+            0x87 => Self::Data2,
+            0x0f => Self::MData,
+            0xd2 => Self::Ack,
+            0x5a => Self::Nak,
+            0x1e => Self::Stall,
+            0x96 => Self::Nyet,
+            0x3c => Self::Pre,
+            0x78 => Self::Split,
+            0xb4 => Self::Ping,
+            _ => return None,
+        })
+    }
+
+    pub fn is_data(self) -> bool {
+        matches!(self, Self::Data0 | Self::Data1 | Self::Data2 | Self::MData)
+    }
+}
+
+pub const REQUEST_KIND_STANDARD: u8 = 0;
+pub const REQUEST_KIND_CLASS: u8 = 1;
+
+pub const RECIPIENT_DEVICE: u8 = 0;
+pub const RECIPIENT_INTERFACE: u8 = 1;
+pub const RECIPIENT_ENDPOINT: u8 = 2;
+
+pub const REQUEST_SET_ADDRESS: u8 = 0x05;
+pub const REQUEST_GET_DESCRIPTOR: u8 = 0x06;
+pub const REQUEST_SET_CONFIGURATION: u8 = 0x09;
+pub const REQUEST_SET_INTERFACE: u8 = 0x0b;
+
+pub const DESCRIPTOR_TYPE_DEVICE: u8 = 0x01;
+pub const DESCRIPTOR_TYPE_CONFIGURATION: u8 = 0x02;
+
+/// payload of a high-bandwidth isochronous endpoint with three transactions
+/// per microframe
+pub const MAX_ISOCHRONOUS_PAYLOAD: usize = 3 * 1024;
+
+bitfield::bitfield! {
+    struct TokenBits([u8]);
+    impl Debug;
+
+    u8, address, _: 14, 8;
+    u8, endpoint, _: 18, 15;
+    /// of SOF packets, which have no address and endpoint
+    u16, frame_number, _: 18, 8;
+}
+
+bitfield::bitfield! {
+    struct SplitBits([u8]);
+    impl Debug;
+
+    u8, hub, _: 14, 8;
+    complete, _: 15;
+    u8, port, _: 22, 16;
+    start, _: 23;
+    end, _: 24;
+    u8, endpoint_type, _: 26, 25;
+}
+
+bitfield::bitfield! {
+    #[derive(Clone, Copy)]
+    pub struct SetupPacket([u8]);
+    impl Debug;
+
+    pub u8, request_type, _: 7, 0;
+    pub u8, recipient, _: 4, 0;
+    /// standard, class or vendor
+    pub u8, kind, _: 6, 5;
+    pub device_to_host, _: 7;
+    pub u8, request, _: 15, 8;
+    pub u16, value, _: 31, 16;
+    pub u16, index, _: 47, 32;
+    pub u16, length, _: 63, 48;
+}
+
+/// A token packet, which starts a transaction.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Token {
+    /// OUT, IN, SETUP or PING
+    pub pid: Pid,
+    pub address: u8,
+    pub endpoint: u8,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum EndpointType {
+    Control,
+    Isochronous,
+    Bulk,
+    Interrupt,
+}
+
+/// The SPLIT token a high-speed hub receives in front of a transaction to a
+/// full- or low-speed device behind it.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Split {
+    /// the address of the hub
+    pub hub: u8,
+    /// CSPLIT, if set, SSPLIT otherwise
+    pub complete: bool,
+    pub port: u8,
+    /// the S bit: low speed for interrupt and control transactions, or the
+    /// payload position of isochronous OUT transactions together with `end`
+    pub start: bool,
+    /// the E bit
+    pub end: bool,
+    pub endpoint_type: EndpointType,
+}
+
+/// A USB packet, decoded by its PID.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Packet<'a> {
+    Token(Token),
+    Sof {
+        frame_number: u16,
+    },
+    Data {
+        pid: Pid,
+        payload: &'a [u8],
+    },
+    /// ACK, NAK, STALL or NYET
+    Handshake(Pid),
+    Split(Split),
+    Pre,
+    /// a packet with damaged check bits, a reserved PID or a wrong length
+    Invalid,
+}
+
+impl<'a> Packet<'a> {
+    /// decodes `data`, which starts with the PID.
+    ///
+    /// The CRC is checked by `is_valid`, so damaged payloads can still be used.
+    pub fn parse(data: &'a [u8]) -> Self {
+        let Some(pid) = data.first().and_then(|v| Pid::from_byte(*v)) else {
+            return Self::Invalid;
+        };
+
+        match pid {
+            Pid::Out | Pid::In | Pid::Setup | Pid::Ping if data.len() == 3 => {
+                let bits = TokenBits(data);
+                Self::Token(Token {
+                    pid,
+                    address: bits.address(),
+                    endpoint: bits.endpoint(),
+                })
+            }
+            Pid::Sof if data.len() == 3 => Self::Sof {
+                frame_number: TokenBits(data).frame_number(),
+            },
+            Pid::Data0 | Pid::Data1 | Pid::Data2 | Pid::MData if data.len() >= 3 => Self::Data {
+                pid,
+                payload: &data[1..data.len() - 2],
+            },
+            Pid::Ack | Pid::Nak | Pid::Stall | Pid::Nyet if data.len() == 1 => Self::Handshake(pid),
+            Pid::Split if data.len() == 4 => {
+                let bits = SplitBits(data);
+                Self::Split(Split {
+                    hub: bits.hub(),
+                    complete: bits.complete(),
+                    port: bits.port(),
+                    start: bits.start(),
+                    end: bits.end(),
+                    endpoint_type: match bits.endpoint_type() {
+                        0 => EndpointType::Control,
+                        1 => EndpointType::Isochronous,
+                        2 => EndpointType::Bulk,
+                        _ => EndpointType::Interrupt,
+                    },
+                })
+            }
+            Pid::Pre if data.len() == 1 => Self::Pre,
+            _ => Self::Invalid,
+        }
+    }
+}
+
+/// returns the CRC5 of the lowest `bits` bits of a token, SOF or SPLIT packet.
+fn crc5(value: u32, bits: u32) -> u8 {
+    let mut crc: u8 = 0x1f;
+    for i in 0..bits {
+        let bit = ((value >> i) & 1) as u8;
+        crc = if (crc ^ bit) & 1 != 0 {
+            (crc >> 1) ^ 0x14
+        } else {
+            crc >> 1
+        };
+    }
+    !crc & 0x1f
+}
+
+/// returns the CRC16 of the payload of a data packet.
+fn crc16(data: &[u8]) -> u16 {
+    let mut crc: u16 = 0xffff;
+    for byte in data {
+        crc ^= u16::from(*byte);
+        for _ in 0..8 {
+            crc = if crc & 1 != 0 {
+                (crc >> 1) ^ 0xa001
+            } else {
+                crc >> 1
+            };
+        }
+    }
+    !crc
+}
+
+/// returns false, if the PID check bits or the CRC of `packet` don't match.
+pub fn is_valid(packet: &[u8]) -> bool {
+    let Some(&pid) = packet.first() else {
+        return false;
+    };
+    if pid >> 4 != !pid & 0x0f {
+        return false;
+    }
+
+    match pid & 0x03 {
+        // token and SOF
+        0x01 => {
+            if packet.len() != 3 {
+                return false;
+            }
+
+            let value = u32::from(u16::from_le_bytes([packet[1], packet[2]]));
+            crc5(value & 0x7ff, 11) == (value >> 11) as u8
+        }
+        // data
+        0x03 => {
+            if packet.len() < 3 {
+                return false;
+            }
+
+            let (payload, crc) = packet[1..].split_at(packet.len() - 3);
+            crc16(payload) == u16::from_le_bytes([crc[0], crc[1]])
+        }
+        // handshake
+        0x02 => packet.len() == 1,
+        // special
+        _ => match Pid::from_byte(pid) {
+            Some(Pid::Ping) if packet.len() == 3 => {
+                let value = u32::from(u16::from_le_bytes([packet[1], packet[2]]));
+                crc5(value & 0x7ff, 11) == (value >> 11) as u8
+            }
+            Some(Pid::Split) if packet.len() == 4 => {
+                let value = u32::from_le_bytes([packet[1], packet[2], packet[3], 0]);
+                crc5(value & 0x7ffff, 19) == (value >> 19) as u8
+            }
+            Some(Pid::Pre) => packet.len() == 1,
+            _ => false,
+        },
+    }
+}
+
+/// A token with the data and handshake packets that followed it.
+#[derive(Clone, Debug)]
+pub struct Transaction {
+    /// the SPLIT token in front of `token`, for devices behind a high-speed hub
+    pub split: Option<Split>,
+    pub token: Token,
+    /// the PID of the data packet, if there was one
+    pub data_pid: Option<Pid>,
+    pub payload: Vec<u8>,
+    /// ACK, NAK, STALL, NYET, or PRE for the ERR of a hub
+    pub handshake: Option<Pid>,
+    /// whether one of the packets after the token was damaged
+    pub damaged: bool,
+}
+
+/// Groups the packets on the bus into transactions.
+///
+/// Isochronous transactions have no handshake, so a transaction without one
+/// is only complete once the next packet starts something else.
+pub struct TransactionAssembler {
+    /// the transaction the following packets belong to
+    current: Transaction,
+    /// whether `current` was started and isn't complete yet
+    pending: bool,
+    /// a SPLIT token waiting for the token it belongs to
+    split: Option<Split>,
+    completed: Transaction,
+}
+
+impl Default for TransactionAssembler {
+    fn default() -> Self {
+        let transaction = || Transaction {
+            split: None,
+            token: Token {
+                pid: Pid::Out,
+                address: 0,
+                endpoint: 0,
+            },
+            data_pid: None,
+            payload: Vec::with_capacity(MAX_ISOCHRONOUS_PAYLOAD),
+            handshake: None,
+            damaged: false,
+        };
+
+        Self {
+            current: transaction(),
+            pending: false,
+            split: None,
+            completed: transaction(),
+        }
+    }
+}
+
+impl TransactionAssembler {
+    /// returns the transaction, if `packet` completed it or started the
+    /// next one.
+    ///
+    /// `valid` is false, if the packet was damaged. A damaged token starts
+    /// nothing, since the device ignores it as well.
+    pub fn packet_received(&mut self, packet: &Packet, valid: bool) -> Option<&Transaction> {
+        let completed = match packet {
+            Packet::Token(token) if valid => {
+                let completed = self.complete();
+                self.current.split = self.split.take();
+                self.current.token = *token;
+                self.current.data_pid = None;
+                self.current.payload.clear();
+                self.current.handshake = None;
+                self.current.damaged = false;
+                self.pending = true;
+                completed
+            }
+            Packet::Split(split) if valid => {
+                let completed = self.complete();
+                self.split = Some(*split);
+                completed
+            }
+            Packet::Data { pid, payload } if self.pending && self.current.data_pid.is_none() => {
+                self.current.data_pid = Some(*pid);
+                self.current.payload.extend_from_slice(payload);
+                self.current.damaged |= !valid;
+                false
+            }
+            Packet::Handshake(pid) if self.pending => {
+                self.current.handshake = Some(*pid);
+                self.current.damaged |= !valid;
+                self.complete()
+            }
+            Packet::Pre if self.pending && self.current.split.is_some() => {
+                self.current.handshake = Some(Pid::Pre);
+                self.current.damaged |= !valid;
+                self.complete()
+            }
+            // the handshake might still follow
+            Packet::Invalid if self.pending => {
+                self.current.damaged = true;
+                false
+            }
+            _ => {
+                self.split = None;
+                self.complete()
+            }
+        };
+
+        completed.then_some(&self.completed)
+    }
+
+    /// moves the pending transaction to `completed` and returns whether there
+    /// was one.
+    fn complete(&mut self) -> bool {
+        if !std::mem::take(&mut self.pending) {
+            return false;
+        }
+
+        std::mem::swap(&mut self.current, &mut self.completed);
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// SETUP to address 0, endpoint 0
+    const SETUP: [u8; 3] = [0x2d, 0x00, 0x10];
+    /// GET_DESCRIPTOR(DEVICE) with a length of 64
+    const DATA0_GET_DESCRIPTOR: [u8; 11] = [
+        0xc3, 0x80, 0x06, 0x00, 0x01, 0x00, 0x00, 0x40, 0x00, 0xdd, 0x94,
+    ];
+
+    /// returns a SPLIT token for port 4 of hub 2.
+    fn split(complete: bool, start: bool, endpoint_type: u32) -> [u8; 4] {
+        let value =
+            2 | u32::from(complete) << 7 | 4 << 8 | u32::from(start) << 15 | endpoint_type << 17;
+        let value = value | u32::from(crc5(value, 19)) << 19;
+        let bytes = value.to_le_bytes();
+        [Pid::Split as u8, bytes[0], bytes[1], bytes[2]]
+    }
+
+    /// returns the transactions the packets were grouped into.
+    fn assemble(packets: &[&[u8]]) -> Vec<Transaction> {
+        let mut assembler = TransactionAssembler::default();
+        packets
+            .iter()
+            .filter_map(|p| {
+                assembler
+                    .packet_received(&Packet::parse(p), is_valid(p))
+                    .cloned()
+            })
+            .collect()
+    }
+
+    #[test]
+    fn crc5_vectors() {
+        // from the CRC examples accompanying the USB 2.0 specification, which
+        // list the check bits in the order they are sent
+        assert_eq!(crc5(0x15 | 0xe << 7, 11).reverse_bits() >> 3, 0x17);
+        assert_eq!(crc5(0x3a | 0xa << 7, 11).reverse_bits() >> 3, 0x1c);
+        assert_eq!(crc5(0x710, 11).reverse_bits() >> 3, 0x14);
+    }
+
+    #[test]
+    fn crc16_vectors() {
+        assert_eq!(crc16(&[0x00, 0x01, 0x02, 0x03]).reverse_bits(), 0xf75e);
+        assert_eq!(crc16(&[0x23, 0x45, 0x67, 0x89]).reverse_bits(), 0x7038);
+        assert_eq!(crc16(&[]), 0x0000);
+    }
+
+    #[test]
+    fn token() {
+        assert!(is_valid(&SETUP));
+        assert_eq!(
+            Packet::parse(&SETUP),
+            Packet::Token(Token {
+                pid: Pid::Setup,
+                address: 0,
+                endpoint: 0,
+            })
+        );
+
+        let out = [0xe1, 0x15, 0xef];
+        assert!(is_valid(&out));
+        assert_eq!(
+            Packet::parse(&out),
+            Packet::Token(Token {
+                pid: Pid::Out,
+                address: 0x15,
+                endpoint: 0xe,
+            })
+        );
+    }
+
+    #[test]
+    fn sof() {
+        let sof = [0xa5, 0x10, 0x2f];
+        assert!(is_valid(&sof));
+        assert_eq!(
+            Packet::parse(&sof),
+            Packet::Sof {
+                frame_number: 0x710
+            }
+        );
+    }
+
+    #[test]
+    fn data() {
+        assert!(is_valid(&DATA0_GET_DESCRIPTOR));
+        assert_eq!(
+            Packet::parse(&DATA0_GET_DESCRIPTOR),
+            Packet::Data {
+                pid: Pid::Data0,
+                payload: &DATA0_GET_DESCRIPTOR[1..9],
+            }
+        );
+
+        // zero-length packet
+        assert!(is_valid(&[0x4b, 0x00, 0x00]));
+        assert_eq!(
+            Packet::parse(&[0x4b, 0x00, 0x00]),
+            Packet::Data {
+                pid: Pid::Data1,
+                payload: &[],
+            }
+        );
+    }
+
+    #[test]
+    fn split_token() {
+        let packet = split(true, false, 1);
+        assert!(is_valid(&packet));
+        assert_eq!(
+            Packet::parse(&packet),
+            Packet::Split(Split {
+                hub: 2,
+                complete: true,
+                port: 4,
+                start: false,
+                end: false,
+                endpoint_type: EndpointType::Isochronous,
+            })
+        );
+    }
+
+    #[test]
+    fn handshake() {
+        assert!(is_valid(&[0xd2]));
+        assert_eq!(Packet::parse(&[0xd2]), Packet::Handshake(Pid::Ack));
+        assert!(!is_valid(&[0xd2, 0x00]));
+        assert_eq!(Packet::parse(&[0xd2, 0x00]), Packet::Invalid);
+    }
+
+    #[test]
+    fn corrupted() {
+        let mut data = DATA0_GET_DESCRIPTOR;
+        data[3] ^= 0x01;
+        assert!(!is_valid(&data));
+        // the payload is still decoded
+        assert!(matches!(Packet::parse(&data), Packet::Data { .. }));
+
+        assert!(!is_valid(&[0x2d, 0x00, 0x11]));
+        assert!(!is_valid(&[0xa5, 0x10, 0xa6]));
+
+        let mut packet = split(false, true, 3);
+        packet[1] ^= 0x01;
+        assert!(!is_valid(&packet));
+
+        // PID check bits
+        assert!(!is_valid(&[0x2c, 0x00, 0x10]));
+        assert!(!is_valid(&[0xd3]));
+        // reserved PID
+        assert!(!is_valid(&[0xf0]));
+        assert_eq!(Packet::parse(&[0xf0]), Packet::Invalid);
+        assert!(!is_valid(&[]));
+        assert_eq!(Packet::parse(&[]), Packet::Invalid);
+    }
+
+    #[test]
+    fn setup_transaction() {
+        let transactions = assemble(&[&SETUP, &DATA0_GET_DESCRIPTOR, &[0xd2]]);
+
+        assert_eq!(transactions.len(), 1);
+        let transaction = &transactions[0];
+        assert_eq!(transaction.token.pid, Pid::Setup);
+        assert_eq!(transaction.data_pid, Some(Pid::Data0));
+        assert_eq!(transaction.payload, DATA0_GET_DESCRIPTOR[1..9]);
+        assert_eq!(transaction.handshake, Some(Pid::Ack));
+        assert!(!transaction.damaged);
+    }
+
+    #[test]
+    fn isochronous_without_handshake() {
+        let out = [0xe1, 0x15, 0xef];
+        let transactions = assemble(&[
+            &out,
+            &[0xc3, 0x00, 0x01, 0x02, 0x03, 0xef, 0x7a],
+            &[0xa5, 0x10, 0x2f],
+            &out,
+            &[0xc3, 0x23, 0x45, 0x67, 0x89, 0x0e, 0x1c],
+            &out,
+        ]);
+
+        // the SOF and the next token complete the transactions
+        assert_eq!(transactions.len(), 2);
+        assert_eq!(transactions[0].payload, [0x00, 0x01, 0x02, 0x03]);
+        assert_eq!(transactions[1].payload, [0x23, 0x45, 0x67, 0x89]);
+        for transaction in &transactions {
+            assert_eq!(transaction.handshake, None);
+            assert!(!transaction.damaged);
+        }
+    }
+
+    #[test]
+    fn complete_split_with_err() {
+        let start = split(false, false, 3);
+        let complete = split(true, false, 3);
+        let transactions = assemble(&[
+            &start,
+            &[0x69, 0x15, 0xef],
+            &[0x5a],
+            &complete,
+            &[0x69, 0x15, 0xef],
+            &[0x3c],
+            &SETUP,
+        ]);
+
+        assert_eq!(transactions.len(), 2);
+        assert_eq!(transactions[0].split.map(|s| s.complete), Some(false));
+        assert_eq!(transactions[0].handshake, Some(Pid::Nak));
+
+        assert_eq!(transactions[1].split.map(|s| s.complete), Some(true));
+        assert_eq!(transactions[1].token.pid, Pid::In);
+        assert_eq!(transactions[1].data_pid, None);
+        assert_eq!(transactions[1].handshake, Some(Pid::Pre));
+        assert!(!transactions[1].damaged);
+    }
+
+    #[test]
+    fn damaged_data_followed_by_ack() {
+        let mut data = DATA0_GET_DESCRIPTOR;
+        data[9] ^= 0xff;
+        let transactions = assemble(&[&SETUP, &data, &[0xd2]]);
+
+        assert_eq!(transactions.len(), 1);
+        assert_eq!(transactions[0].payload, DATA0_GET_DESCRIPTOR[1..9]);
+        assert_eq!(transactions[0].handshake, Some(Pid::Ack));
+        assert!(transactions[0].damaged);
+    }
+
+    #[test]
+    fn token_after_token() {
+        let transactions = assemble(&[&SETUP, &[0xe1, 0x15, 0xef], &[0xd2]]);
+
+        assert_eq!(transactions.len(), 2);
+        assert_eq!(transactions[0].token.pid, Pid::Setup);
+        assert_eq!(transactions[0].data_pid, None);
+        assert_eq!(transactions[0].handshake, None);
+        assert_eq!(transactions[1].token.pid, Pid::Out);
+        assert_eq!(transactions[1].handshake, Some(Pid::Ack));
+    }
+
+    #[test]
+    fn damaged_token_starts_nothing() {
+        let transactions = assemble(&[&[0x2d, 0x00, 0x11], &DATA0_GET_DESCRIPTOR, &[0xd2]]);
+
+        assert!(transactions.is_empty());
+    }
+}